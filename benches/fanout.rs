@@ -0,0 +1,112 @@
+//! Compares delivering a broadcast event to every connection of a room
+//! by serializing it per receiver against sharing its pre-encoded frames.
+//!
+//! socketeer is a binary crate so the encoding module is included directly,
+//! it has no dependencies on the rest of the crate.
+
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use poem::web::websocket::Message;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+
+#[allow(dead_code)]
+#[path = "../src/encoding.rs"]
+mod encoding;
+
+use encoding::{Compression, EncodedFrames, Encoding};
+
+const RECEIVERS: &[usize] = &[100, 1_000, 5_000];
+
+/// A stand-in for a broadcast event carrying its encoded frames, the
+/// gateway's `Event` can't be imported from the binary crate.
+#[derive(Serialize, Debug, Clone)]
+struct Event {
+    #[serde(rename = "type")]
+    type_: String,
+    data: Value,
+    seq: Option<u64>,
+
+    #[serde(skip)]
+    frames: EncodedFrames,
+}
+
+/// A playlist snapshot of around 10KB.
+fn playlist_event() -> Event {
+    let tracks: Vec<Value> = (0..100)
+        .map(|i| json!({
+            "id": i,
+            "title": format!("Track number {}", i),
+            "artist": "Some artist",
+            "duration": 180 + i,
+        }))
+        .collect();
+
+    Event {
+        type_: "PLAYLIST_UPDATE".to_string(),
+        data: json!({ "tracks": tracks }),
+        seq: Some(1),
+        frames: Default::default(),
+    }
+}
+
+fn per_receiver(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout/per_receiver");
+    group.sample_size(20);
+
+    for &amount in RECEIVERS {
+        let (tx, _) = broadcast::channel::<Event>(16);
+        let mut receivers: Vec<_> = (0..amount).map(|_| tx.subscribe()).collect();
+        let event = playlist_event();
+
+        group.throughput(Throughput::Elements(amount as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(amount), &amount, |b, _| {
+            b.iter(|| {
+                tx.send(event.clone()).unwrap();
+
+                for receiver in receivers.iter_mut() {
+                    let event = receiver.try_recv().unwrap();
+                    black_box(Message::Binary(serde_json::to_vec(&event).unwrap()));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn shared_frames(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout/shared_frames");
+    group.sample_size(20);
+
+    for &amount in RECEIVERS {
+        let (tx, _) = broadcast::channel::<Arc<Event>>(16);
+        let mut receivers: Vec<_> = (0..amount).map(|_| tx.subscribe()).collect();
+        let event = playlist_event();
+
+        group.throughput(Throughput::Elements(amount as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(amount), &amount, |b, _| {
+            b.iter(|| {
+                // Every emit starts with no frames, as after `Event::set_seq`.
+                let event = Event { frames: Default::default(), ..event.clone() };
+                event.frames.get_or_encode(Encoding::Json, Compression::None, &event).unwrap();
+                tx.send(Arc::new(event)).unwrap();
+
+                for receiver in receivers.iter_mut() {
+                    let event = receiver.try_recv().unwrap();
+                    let frame = event.frames
+                        .get_or_encode(Encoding::Json, Compression::None, &*event)
+                        .unwrap();
+                    black_box(frame.to_message());
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, per_receiver, shared_frames);
+criterion_main!(benches);
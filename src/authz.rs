@@ -0,0 +1,244 @@
+//! The rules deciding who can join and publish to a room.
+//!
+//! Rooms are joined by the first rule which applies:
+//!
+//! 1. Inactive rooms can't be joined by anyone.
+//! 2. The owner can always join.
+//! 3. Invite only rooms can only be joined by invited users, regardless
+//!    of `is_public` or the room's guild.
+//! 4. Public rooms can be joined by anyone.
+//! 5. Rooms with a `guild_id` can be joined by members of the guild, a user
+//!    is a member if the guild is a key in their `access_servers`.
+//! 6. Everyone else is denied.
+//!
+//! Only the owner and members of the room's guild can publish events,
+//! everyone else is a listener.
+
+use std::fmt::{Display, Formatter};
+
+use anyhow::Result;
+
+use crate::models::{Room, User};
+use crate::storage::Storage;
+
+
+/// Why a user isn't allowed to join a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denial {
+    /// The room is no longer active.
+    RoomInactive,
+
+    /// The room is invite only and the user hasn't been invited.
+    NotInvited,
+
+    /// The room is limited to a guild the user isn't a member of.
+    NotGuildMember,
+
+    /// The room is private.
+    Private,
+}
+
+impl Denial {
+    /// A stable identifier for the denial clients can match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::RoomInactive => "ROOM_INACTIVE",
+            Self::NotInvited => "NOT_INVITED",
+            Self::NotGuildMember => "NOT_GUILD_MEMBER",
+            Self::Private => "PRIVATE",
+        }
+    }
+}
+
+impl Display for Denial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let detail = match self {
+            Self::RoomInactive => "room closed",
+            Self::NotInvited => "no access, the room is invite only",
+            Self::NotGuildMember => "no access, the room is limited to guild members",
+            Self::Private => "no access, the room is private",
+        };
+
+        f.write_str(detail)
+    }
+}
+
+
+/// The outcome of evaluating the policy without any storage lookups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny(Denial),
+
+    /// The user can join if they have been invited to the room.
+    RequiresInvite,
+}
+
+/// Evaluates the join rules for the user.
+pub fn evaluate(user: &User, room: &Room) -> Decision {
+    if !room.active {
+        return Decision::Deny(Denial::RoomInactive)
+    }
+
+    if is_owner(user, room) {
+        return Decision::Allow
+    }
+
+    if room.invite_only {
+        return Decision::RequiresInvite
+    }
+
+    if room.is_public {
+        return Decision::Allow
+    }
+
+    match room.guild_id {
+        Some(_) if is_guild_member(user, room) => Decision::Allow,
+        Some(_) => Decision::Deny(Denial::NotGuildMember),
+        None => Decision::Deny(Denial::Private),
+    }
+}
+
+/// Checks if the user can join the room, looking up their invite if needed.
+pub async fn authorize(storage: &dyn Storage, user: &User, room: &Room) -> Result<Result<(), Denial>> {
+    let decision = match evaluate(user, room) {
+        Decision::Allow => Ok(()),
+        Decision::Deny(denial) => Err(denial),
+        Decision::RequiresInvite => {
+            if storage.is_invited(room.id, *user.id).await? {
+                Ok(())
+            } else {
+                Err(Denial::NotInvited)
+            }
+        },
+    };
+
+    Ok(decision)
+}
+
+/// Checks if the user can publish events to the room.
+pub fn can_publish(user: &User, room: &Room) -> bool {
+    is_owner(user, room) || is_guild_member(user, room)
+}
+
+fn is_owner(user: &User, room: &Room) -> bool {
+    room.owner_id == user.id
+}
+
+fn is_guild_member(user: &User, room: &Room) -> bool {
+    room.guild_id
+        .map(|guild_id| user.access_servers.contains_key(&guild_id))
+        .unwrap_or(false)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::utils::JsSafeBigInt;
+
+    const OWNER_ID: i64 = 1;
+    const USER_ID: i64 = 2;
+    const GUILD_ID: i64 = 10;
+
+    fn user(id: i64, guilds: &[i64]) -> User {
+        User {
+            id: JsSafeBigInt(id),
+            access_servers: guilds.iter().map(|id| (*id, false)).collect(),
+            avatar: None,
+            updated_on: 0,
+            username: format!("user-{}", id),
+        }
+    }
+
+    fn room() -> Room {
+        Room {
+            id: Uuid::nil(),
+            owner_id: JsSafeBigInt(OWNER_ID),
+            active: true,
+            active_playlist: None,
+            banner: None,
+            guild_id: None,
+            invite_only: false,
+            is_public: false,
+            playing_now: None,
+            title: "room".to_string(),
+            topic: None,
+            lag_policy: None,
+            broadcast_capacity: None,
+        }
+    }
+
+    #[test]
+    fn inactive_rooms_deny_everyone() {
+        let room = Room { active: false, is_public: true, ..room() };
+
+        assert_eq!(evaluate(&user(OWNER_ID, &[]), &room), Decision::Deny(Denial::RoomInactive));
+        assert_eq!(evaluate(&user(USER_ID, &[]), &room), Decision::Deny(Denial::RoomInactive));
+    }
+
+    #[test]
+    fn owner_is_always_allowed() {
+        let room = Room { invite_only: true, guild_id: Some(JsSafeBigInt(GUILD_ID)), ..room() };
+
+        assert_eq!(evaluate(&user(OWNER_ID, &[]), &room), Decision::Allow);
+    }
+
+    #[test]
+    fn invite_only_overrides_public_and_guild() {
+        let room = Room {
+            invite_only: true,
+            is_public: true,
+            guild_id: Some(JsSafeBigInt(GUILD_ID)),
+            ..room()
+        };
+
+        assert_eq!(evaluate(&user(USER_ID, &[GUILD_ID]), &room), Decision::RequiresInvite);
+    }
+
+    #[test]
+    fn public_rooms_allow_anyone() {
+        let room = Room { is_public: true, ..room() };
+
+        assert_eq!(evaluate(&user(USER_ID, &[]), &room), Decision::Allow);
+    }
+
+    #[test]
+    fn guild_rooms_require_membership() {
+        let room = Room { guild_id: Some(JsSafeBigInt(GUILD_ID)), ..room() };
+
+        assert_eq!(evaluate(&user(USER_ID, &[GUILD_ID]), &room), Decision::Allow);
+        assert_eq!(evaluate(&user(USER_ID, &[GUILD_ID + 1]), &room), Decision::Deny(Denial::NotGuildMember));
+    }
+
+    #[test]
+    fn private_rooms_deny_everyone_else() {
+        assert_eq!(evaluate(&user(USER_ID, &[GUILD_ID]), &room()), Decision::Deny(Denial::Private));
+    }
+
+    #[test]
+    fn only_owner_and_guild_members_publish() {
+        let room = Room { is_public: true, guild_id: Some(JsSafeBigInt(GUILD_ID)), ..room() };
+
+        assert!(can_publish(&user(OWNER_ID, &[]), &room));
+        assert!(can_publish(&user(USER_ID, &[GUILD_ID]), &room));
+        assert!(!can_publish(&user(USER_ID, &[]), &room));
+    }
+
+    #[tokio::test]
+    async fn authorize_looks_up_invites() {
+        let storage = MemoryStorage::from_fixture_file("tests/fixtures/storage.json").unwrap();
+        let room = storage
+            .get_room_by_id(Uuid::parse_str("3f1f8a52-6a3e-4b52-9d7e-2c0a4f6b1e90").unwrap())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let invited = user(123456789012345678, &[]);
+        assert_eq!(authorize(&storage, &invited, &room).await.unwrap(), Ok(()));
+        assert_eq!(authorize(&storage, &user(USER_ID, &[]), &room).await.unwrap(), Err(Denial::NotInvited));
+    }
+}
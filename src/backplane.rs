@@ -0,0 +1,345 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use crate::emitter::ConnectionCommand;
+use crate::models::Room;
+use crate::storage::CacheInvalidation;
+use crate::ws::{Event, Targets};
+
+/// The redis channel every socketeer instance publishes to.
+const REDIS_CHANNEL: &str = "socketeer:cluster";
+
+/// How long to wait before re-connecting a dropped redis subscription.
+const REDIS_RECONNECT_DELAY: Duration = Duration::from_secs(2);
+
+
+/// A message shared between socketeer instances.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClusterMessage {
+    /// An event emitted to a room.
+    Emit {
+        room_id: Uuid,
+        event: Event,
+
+        #[serde(default)]
+        targets: Option<Targets>,
+    },
+
+    /// A room being force closed.
+    CloseRoom {
+        room_id: Uuid,
+        warn_clients: bool,
+    },
+
+    /// A command for every connection of a user.
+    UserCommand {
+        user_id: i64,
+        command: ConnectionCommand,
+    },
+
+    /// A room's state which has changed.
+    UpdateRoom {
+        room: Room,
+    },
+
+    /// Access tokens which have been revoked.
+    RevokeTokens {
+        access_tokens: Vec<String>,
+        reason: String,
+    },
+
+    /// Cached storage entries which have changed.
+    InvalidateCache {
+        invalidation: CacheInvalidation,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    /// The id of the instance which published the message.
+    pub origin: Uuid,
+    pub message: ClusterMessage,
+}
+
+
+/// The transport used to fan out messages to every socketeer instance.
+#[async_trait]
+pub trait Backplane: Send + Sync + 'static {
+    /// Publishes the message to every subscribed instance, including
+    /// the publisher itself.
+    async fn publish(&self, envelope: &Envelope) -> Result<()>;
+
+    /// Subscribes to the messages published by all instances.
+    fn subscribe(&self) -> mpsc::Receiver<Envelope>;
+
+    /// If other instances may be attached to the backplane.
+    fn is_clustered(&self) -> bool;
+}
+
+
+/// An in-process backplane.
+///
+/// Used when running a single instance, clones of the backplane can be
+/// shared between several managers to simulate a cluster.
+#[derive(Clone)]
+pub struct LoopbackBackplane {
+    sender: broadcast::Sender<Envelope>,
+}
+
+impl Default for LoopbackBackplane {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(256).0,
+        }
+    }
+}
+
+#[async_trait]
+impl Backplane for LoopbackBackplane {
+    async fn publish(&self, envelope: &Envelope) -> Result<()> {
+        // No subscribers simply means there is nobody to deliver to.
+        let _ = self.sender.send(envelope.clone());
+        Ok(())
+    }
+
+    fn subscribe(&self) -> mpsc::Receiver<Envelope> {
+        let mut receiver = self.sender.subscribe();
+        let (tx, rx) = mpsc::channel(256);
+
+        tokio::spawn(async move {
+            loop {
+                let envelope = match receiver.recv().await {
+                    Ok(envelope) => envelope,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Loopback backplane subscriber lagged behind, {} messages skipped.", n);
+                        continue;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                if tx.send(envelope).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        rx
+    }
+
+    fn is_clustered(&self) -> bool {
+        self.sender.receiver_count() > 1
+    }
+}
+
+
+/// A backplane using redis pub/sub.
+pub struct RedisBackplane {
+    client: redis::Client,
+
+    /// Re-connects on its own after the connection to redis is lost.
+    publisher: ConnectionManager,
+}
+
+impl RedisBackplane {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let publisher = client.get_tokio_connection_manager().await?;
+
+        Ok(Self {
+            client,
+            publisher,
+        })
+    }
+}
+
+#[async_trait]
+impl Backplane for RedisBackplane {
+    async fn publish(&self, envelope: &Envelope) -> Result<()> {
+        let payload = serde_json::to_string(envelope)?;
+
+        let mut conn = self.publisher.clone();
+        conn.publish::<_, _, ()>(REDIS_CHANNEL, payload).await?;
+
+        Ok(())
+    }
+
+    fn subscribe(&self) -> mpsc::Receiver<Envelope> {
+        let client = self.client.clone();
+        let (tx, rx) = mpsc::channel(256);
+
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                if let Err(e) = forward_redis_messages(&client, &tx).await {
+                    error!("Redis backplane subscription failed: {}, reconnecting...", e);
+                }
+
+                tokio::time::sleep(REDIS_RECONNECT_DELAY).await;
+            }
+        });
+
+        rx
+    }
+
+    fn is_clustered(&self) -> bool {
+        true
+    }
+}
+
+async fn forward_redis_messages(client: &redis::Client, tx: &mpsc::Sender<Envelope>) -> Result<()> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(REDIS_CHANNEL).await?;
+    info!("Subscribed to redis backplane channel {}", REDIS_CHANNEL);
+
+    let mut messages = pubsub.into_on_message();
+    while let Some(msg) = messages.next().await {
+        let payload: String = match msg.get_payload() {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Ignoring invalid backplane message: {}", e);
+                continue;
+            },
+        };
+
+        let envelope = match serde_json::from_str::<Envelope>(&payload) {
+            Ok(envelope) => envelope,
+            Err(e) => {
+                warn!("Ignoring invalid backplane message: {}", e);
+                continue;
+            },
+        };
+
+        if tx.send(envelope).await.is_err() {
+            return Ok(())
+        }
+    }
+
+    Err(anyhow::anyhow!("subscription closed"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::utils::JsSafeBigInt;
+
+    fn event() -> Event {
+        let mut event = Event::new("HELLO", json!({ "greeting": "hi" }));
+        event.set_seq(3);
+        event.coalesce_key = Some("greeting".to_string());
+        event
+    }
+
+    fn room() -> Room {
+        Room {
+            id: Uuid::new_v4(),
+            owner_id: JsSafeBigInt(1),
+            active: true,
+            active_playlist: None,
+            banner: None,
+            guild_id: Some(JsSafeBigInt(10)),
+            invite_only: false,
+            is_public: true,
+            playing_now: None,
+            title: "room".to_string(),
+            topic: None,
+            lag_policy: None,
+            broadcast_capacity: Some(8),
+        }
+    }
+
+    /// Encodes the message the way the redis backplane does and decodes it
+    /// again, returning both encodings to compare.
+    fn round_trip(message: ClusterMessage) -> (Value, Value) {
+        let envelope = Envelope {
+            origin: Uuid::new_v4(),
+            message,
+        };
+
+        let payload = serde_json::to_string(&envelope).unwrap();
+        let decoded: Envelope = serde_json::from_str(&payload).unwrap();
+
+        (serde_json::to_value(&envelope).unwrap(), serde_json::to_value(&decoded).unwrap())
+    }
+
+    #[test]
+    fn emits_round_trip() {
+        let (sent, received) = round_trip(ClusterMessage::Emit {
+            room_id: Uuid::new_v4(),
+            event: event(),
+            targets: Some(Targets {
+                // Single ids as sets aren't encoded in a stable order.
+                user_ids: Some([1].into_iter().collect()),
+                exclude_user_ids: [3].into_iter().collect(),
+            }),
+        });
+        assert_eq!(sent, received);
+    }
+
+    #[test]
+    fn closed_rooms_round_trip() {
+        let (sent, received) = round_trip(ClusterMessage::CloseRoom {
+            room_id: Uuid::new_v4(),
+            warn_clients: true,
+        });
+        assert_eq!(sent, received);
+    }
+
+    #[test]
+    fn user_events_round_trip() {
+        let (sent, received) = round_trip(ClusterMessage::UserCommand {
+            user_id: 1,
+            command: ConnectionCommand::Send(event()),
+        });
+        assert_eq!(sent, received);
+    }
+
+    #[test]
+    fn user_disconnects_round_trip() {
+        let (sent, received) = round_trip(ClusterMessage::UserCommand {
+            user_id: 1,
+            command: ConnectionCommand::Disconnect {
+                reason: "bye".to_string(),
+                code: Some(4001),
+            },
+        });
+        assert_eq!(sent, received);
+    }
+
+    #[test]
+    fn room_updates_round_trip() {
+        let (sent, received) = round_trip(ClusterMessage::UpdateRoom { room: room() });
+        assert_eq!(sent, received);
+    }
+
+    #[test]
+    fn revoked_tokens_round_trip() {
+        let (sent, received) = round_trip(ClusterMessage::RevokeTokens {
+            access_tokens: vec!["token".to_string()],
+            reason: "revoked".to_string(),
+        });
+        assert_eq!(sent, received);
+    }
+
+    #[test]
+    fn cache_invalidations_round_trip() {
+        let (sent, received) = round_trip(ClusterMessage::InvalidateCache {
+            invalidation: CacheInvalidation {
+                user_ids: vec![1],
+                access_tokens: vec!["token".to_string()],
+                room_ids: vec![Uuid::new_v4()],
+                all: false,
+            },
+        });
+        assert_eq!(sent, received);
+    }
+}
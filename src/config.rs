@@ -0,0 +1,405 @@
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use toml::Value;
+
+use crate::models::LagPolicy;
+
+/// The file the config is loaded from unless `SOCKETEER_CONFIG` is set.
+const DEFAULT_CONFIG_PATH: &str = "socketeer.toml";
+
+/// The prefix of environment variables overriding config values,
+/// e.g. `SOCKETEER__SERVER__BIND=0.0.0.0:8800`.
+const ENV_PREFIX: &str = "SOCKETEER__";
+
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub emitter: EmitterConfig,
+    pub backplane: BackplaneConfig,
+    pub storage: StorageConfig,
+    pub cache: CacheConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The address the server listens on.
+    pub bind: String,
+
+    /// The url the server is reachable at, used in the OpenAPI spec.
+    pub public_url: String,
+
+    /// The origins allowed to make cross-origin requests, can't be empty
+    /// as that allows every origin.
+    pub cors_origins: Vec<String>,
+
+    /// How long to wait for connections to close when shutting down in seconds.
+    pub drain_timeout: u64,
+
+    /// How long clients are told to wait before reconnecting when the
+    /// server shuts down in seconds.
+    pub reconnect_retry_after: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8800".to_string(),
+            public_url: "http://127.0.0.1:8800".to_string(),
+            cors_origins: vec![
+                "http://127.0.0.1:3000".to_string(),
+                "http://localhost:3000".to_string(),
+            ],
+            drain_timeout: 10,
+            reconnect_retry_after: 2,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// The ScyllaDB nodes to connect to.
+    pub nodes: Vec<String>,
+    pub keyspace: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            nodes: vec!["127.0.0.1:9042".to_string()],
+            keyspace: "spooderfy".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EmitterConfig {
+    /// How often rooms send a `PING` in seconds.
+    pub keep_alive_ping: u64,
+
+    /// The amount of pings a room can go without any connections
+    /// before it is closed.
+    pub max_interval_misses: u64,
+
+    /// The amount of events a connection can fall behind by before it lags,
+    /// unless set by the room.
+    pub broadcast_capacity: usize,
+
+    /// The largest broadcast capacity a room can set, larger ones are clamped to it.
+    pub max_broadcast_capacity: usize,
+
+    /// How connections falling behind a room are handled unless set by the room.
+    pub lag_policy: LagPolicy,
+
+    /// The amount of events each room keeps for resuming sessions and lagging
+    /// connections, rooms with a larger capacity keep as many more.
+    pub replay_buffer_size: usize,
+
+    /// The amount of coalesce keys each room keeps the latest event of for
+    /// new sessions, the least recently updated key is dropped past it.
+    pub coalesce_key_limit: usize,
+
+    /// How long a session can be resumed for after disconnecting in seconds.
+    pub session_resume_timeout: i64,
+
+    /// How often each connection re-validates its access token in seconds,
+    /// `0` disables it.
+    pub token_revalidate_interval: u64,
+}
+
+impl Default for EmitterConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive_ping: 30,
+            max_interval_misses: 2 * 10,  // 10 minutes of in-activity.
+            broadcast_capacity: 32,
+            max_broadcast_capacity: 1024,
+            lag_policy: LagPolicy::Disconnect,
+            replay_buffer_size: 128,
+            coalesce_key_limit: 256,
+            session_resume_timeout: 5 * 60,
+            token_revalidate_interval: 5 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackplaneConfig {
+    /// The redis server used to fan out events to other instances,
+    /// the in-process loopback is used if not set.
+    pub redis_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Users and rooms are read from ScyllaDB.
+    Scylla,
+
+    /// Users and rooms are held in memory, optionally seeded from a fixture file.
+    Memory,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+
+    /// The JSON fixture the memory backend is seeded from.
+    pub fixture: Option<String>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Scylla,
+            fixture: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// How long resolved tokens, users and rooms are cached for in seconds,
+    /// `0` disables caching.
+    pub ttl: u64,
+
+    /// The most entries each cache holds.
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl: 60,
+            max_entries: 10_000,
+        }
+    }
+}
+
+
+impl Config {
+    /// Loads the config from the file at `SOCKETEER_CONFIG` or `socketeer.toml`
+    /// applying any environment overrides.
+    ///
+    /// The defaults are used if the file doesn't exist.
+    pub fn load() -> Result<Self> {
+        let path = std::env::var("SOCKETEER_CONFIG")
+            .unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+
+        let mut root = if Path::new(&path).exists() {
+            info!("Loading config from {}", &path);
+            let raw = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read config file {}", &path))?;
+
+            raw.parse::<Value>()
+                .with_context(|| format!("failed to parse config file {}", &path))?
+        } else {
+            info!("No config file found at {}, using defaults", &path);
+            Value::Table(Default::default())
+        };
+
+        for (key, value) in std::env::vars() {
+            if let Some(path) = key.strip_prefix(ENV_PREFIX) {
+                apply_override(&mut root, path, &value)
+                    .with_context(|| format!("invalid config override {}", &key))?;
+            }
+        }
+
+        let config: Self = root.try_into().context("invalid config")?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.server.bind
+            .parse::<SocketAddr>()
+            .with_context(|| format!("server.bind is not a valid address: {}", &self.server.bind))?;
+
+        // An empty list allows every origin, with credentials.
+        if self.server.cors_origins.is_empty() {
+            return Err(anyhow!("server.cors_origins must contain at least one origin"))
+        }
+
+        if self.database.nodes.is_empty() {
+            return Err(anyhow!("database.nodes must contain at least one node"))
+        }
+
+        if self.database.keyspace.is_empty() {
+            return Err(anyhow!("database.keyspace must not be empty"))
+        }
+
+        if self.emitter.keep_alive_ping == 0 {
+            return Err(anyhow!("emitter.keep_alive_ping must be greater than 0"))
+        }
+
+        if self.emitter.max_interval_misses == 0 {
+            return Err(anyhow!("emitter.max_interval_misses must be greater than 0"))
+        }
+
+        if self.emitter.broadcast_capacity == 0 {
+            return Err(anyhow!("emitter.broadcast_capacity must be greater than 0"))
+        }
+
+        if self.emitter.max_broadcast_capacity < self.emitter.broadcast_capacity {
+            return Err(anyhow!("emitter.max_broadcast_capacity must be at least emitter.broadcast_capacity"))
+        }
+
+        // Lagging connections recover the events they lost from the replay
+        // buffer, which only holds any once it's larger than the capacity.
+        if self.emitter.replay_buffer_size <= self.emitter.broadcast_capacity {
+            return Err(anyhow!("emitter.replay_buffer_size must be greater than emitter.broadcast_capacity"))
+        }
+
+        if self.emitter.session_resume_timeout < 0 {
+            return Err(anyhow!("emitter.session_resume_timeout must not be negative"))
+        }
+
+        if self.storage.fixture.is_some() && self.storage.backend != StorageBackend::Memory {
+            return Err(anyhow!("storage.fixture is only supported by the memory backend"))
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets the value at the `__` separated path, e.g. `SERVER__BIND`.
+///
+/// Values are parsed as TOML so numbers, booleans and arrays can be given,
+/// anything else is treated as a plain string.
+fn apply_override(root: &mut Value, path: &str, raw: &str) -> Result<()> {
+    let keys: Vec<String> = path
+        .split("__")
+        .map(|key| key.to_lowercase())
+        .collect();
+
+    let (last, parents) = keys
+        .split_last()
+        .ok_or_else(|| anyhow!("empty config path"))?;
+
+    let mut table = root
+        .as_table_mut()
+        .ok_or_else(|| anyhow!("config root is not a table"))?;
+
+    for key in parents {
+        table = table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Default::default()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow!("{} is not a table", key))?;
+    }
+
+    let value = format!("value = {}", raw)
+        .parse::<Value>()
+        .ok()
+        .and_then(|parsed| parsed.get("value").cloned())
+        .unwrap_or_else(|| Value::String(raw.to_string()));
+
+    table.insert(last.clone(), value);
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root() -> Value {
+        r#"
+        [server]
+        bind = "127.0.0.1:8800"
+        "#.parse().unwrap()
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_allowing_every_origin() {
+        let mut config = Config::default();
+        config.server.cors_origins.clear();
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_rooms_closing_on_their_first_ping() {
+        let mut config = Config::default();
+        config.emitter.max_interval_misses = 0;
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_replay_buffer_smaller_than_broadcast_capacity() {
+        let mut config = Config::default();
+        config.emitter.broadcast_capacity = 64;
+        config.emitter.replay_buffer_size = 32;
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_replay_buffer_as_large_as_broadcast_capacity() {
+        let mut config = Config::default();
+        config.emitter.broadcast_capacity = 64;
+        config.emitter.replay_buffer_size = 64;
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_max_broadcast_capacity_below_the_default() {
+        let mut config = Config::default();
+        config.emitter.max_broadcast_capacity = 16;
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn override_replaces_nested_value() {
+        let mut root = root();
+        apply_override(&mut root, "SERVER__BIND", "0.0.0.0:9000").unwrap();
+
+        assert_eq!(root["server"]["bind"].as_str(), Some("0.0.0.0:9000"));
+    }
+
+    #[test]
+    fn override_parses_toml_values() {
+        let mut root = root();
+        apply_override(&mut root, "EMITTER__BROADCAST_CAPACITY", "64").unwrap();
+        apply_override(&mut root, "DATABASE__NODES", r#"["10.0.0.1:9042", "10.0.0.2:9042"]"#).unwrap();
+
+        assert_eq!(root["emitter"]["broadcast_capacity"].as_integer(), Some(64));
+        assert_eq!(root["database"]["nodes"].as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn override_falls_back_to_plain_string() {
+        let mut root = root();
+        apply_override(&mut root, "BACKPLANE__REDIS_URL", "redis://127.0.0.1:6379").unwrap();
+
+        assert_eq!(root["backplane"]["redis_url"].as_str(), Some("redis://127.0.0.1:6379"));
+    }
+
+    #[test]
+    fn override_rejects_path_through_value() {
+        let mut root = root();
+
+        assert!(apply_override(&mut root, "SERVER__BIND__PORT", "9000").is_err());
+    }
+}
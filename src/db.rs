@@ -1,12 +1,17 @@
 use std::fmt::{Debug, Formatter};
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Instant;
 
 use scylla::{QueryResult, SessionBuilder};
 use scylla::frame::value::ValueList;
 use scylla::prepared_statement::PreparedStatement;
 use concread::arcache::{ARCache, ARCacheBuilder};
 
+use crate::config::DatabaseConfig;
+use crate::metrics::METRICS;
+use crate::migrations;
+
 #[derive(Clone)]
 pub struct Session(Arc<scylla::Session>, Arc<ARCache<String, PreppedStmt>>);
 
@@ -38,11 +43,27 @@ impl Session {
         result
     }
 
-    #[instrument(skip(self, query), level = "trace")]
+    /// Executes the query as a prepared statement, preparing and caching
+    /// it on first use.
     pub async fn query_prepared(
         &self,
         query: &str,
         values: impl ValueList + Debug,
+    ) -> anyhow::Result<QueryResult> {
+        let start = Instant::now();
+        let result = self.execute_prepared(query, values).await;
+
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        METRICS.query(start.elapsed().as_secs_f64(), outcome);
+
+        result
+    }
+
+    #[instrument(skip(self, query), level = "trace")]
+    async fn execute_prepared(
+        &self,
+        query: &str,
+        values: impl ValueList + Debug,
     ) -> anyhow::Result<QueryResult> {
         {
             let mut reader = self.1.read();
@@ -118,34 +139,22 @@ impl Deref for PreppedStmt{
 }
 
 
-pub async fn connect(node: &str) -> anyhow::Result<Session> {
+/// Connects to the cluster without selecting a keyspace.
+pub async fn connect_cluster(config: &DatabaseConfig) -> anyhow::Result<scylla::Session> {
     let session = SessionBuilder::new()
-        .known_node(node)
+        .known_nodes(&config.nodes)
         .build()
         .await?;
 
-    let _ = session.query("CREATE KEYSPACE spooderfy WITH replication = {'class': 'SimpleStrategy', 'replication_factor' : 1};", &[]).await;
-    session.use_keyspace("spooderfy", false).await?;
-
-    create_tables(&session).await?;
-
-    Ok(Session::from(session))
+    Ok(session)
 }
 
-async fn create_tables(session: &scylla::Session) -> anyhow::Result<()> {
-    let query_block = include_str!("./scripts/tables.cql");
+/// Connects to the configured keyspace, the schema must be up to date.
+pub async fn connect(config: &DatabaseConfig) -> anyhow::Result<Session> {
+    let session = connect_cluster(config).await?;
 
-    for query in query_block.split("--") {
-        if query.is_empty() {
-            continue;
-        }
-
-        info!("executing {}", query.replace("\r\n", "").replace("    ", " "));
-        session.query(
-            query,
-            &[]
-        ).await?;
-    }
+    session.use_keyspace(&config.keyspace, false).await?;
+    migrations::ensure_up_to_date(&session).await?;
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(Session::from(session))
+}
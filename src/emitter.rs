@@ -1,22 +1,49 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use dashmap::DashMap;
-use tokio::sync::broadcast;
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
 use uuid::Uuid;
-use anyhow::{anyhow, Result};
-use serde_json::Value;
-use tokio::sync::mpsc::Sender;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::mpsc::{self, Sender, UnboundedSender};
 use tokio::task::JoinHandle;
 
-use crate::ws::Event;
+use crate::backplane::{Backplane, ClusterMessage, Envelope};
+use crate::config::EmitterConfig;
+use crate::error::ApiError;
+use crate::metrics::METRICS;
+use crate::models::{Room, User};
+use crate::storage::{CacheInvalidation, CachedStorage};
+use crate::utils::JsSafeBigInt;
+use crate::ws::{Event, Targets, CLOSE_UNAUTHORIZED};
 
-const KEEP_ALIVE_PING: u64 = 30;
-const MAX_INTERVAL_MISSES: u64 = 2 * 10;  // 10 minutes of in-activity.
+/// The amount of commands which can be queued for a single connection.
+const CONNECTION_COMMAND_BUFFER: usize = 32;
 
 pub struct RoomWrapper {
     pub started: i64,
-    pub messenger: broadcast::Sender<Event>,
+    pub messenger: broadcast::Sender<Arc<Event>>,
+
+    /// The amount of events buffered for resuming sessions and lagging
+    /// connections, always more than the broadcast capacity so lost events
+    /// are still buffered when a connection lags.
+    replay_size: usize,
+
+    /// The amount of coalesce keys the latest event is kept for.
+    key_limit: usize,
+    state: Mutex<RoomState>,
+    sessions: Arc<DashMap<Uuid, GatewaySession>>,
+
+    /// The users connected to the room.
+    members: DashMap<i64, Member>,
+
+    /// The latest state of the room, `None` until it's updated.
+    updates: watch::Sender<Option<Room>>,
     handle: JoinHandle<()>,
 }
 
@@ -26,20 +53,245 @@ impl Drop for RoomWrapper {
     }
 }
 
+/// The sequencing state of a room.
+///
+/// Events are sent to the broadcast channel while holding the lock so
+/// subscribers and the replay buffer always agree on the order.
+#[derive(Default)]
+struct RoomState {
+    seq: u64,
+    replay: VecDeque<Arc<Event>>,
+
+    /// The latest event of each coalesce key, delivered to new sessions.
+    /// Targeted events aren't kept as they aren't everyone's state.
+    latest: HashMap<String, Arc<Event>>,
+}
+
+impl RoomState {
+    /// Keeps the event as the latest of its key, dropping the least recently
+    /// updated key if the room already holds `limit` keys.
+    fn keep_latest(&mut self, key: &str, event: &Arc<Event>, limit: usize) {
+        if !self.latest.contains_key(key) && self.latest.len() >= limit {
+            let oldest = self.latest
+                .iter()
+                .min_by_key(|(_, event)| event.seq)
+                .map(|(key, _)| key.clone());
+
+            match oldest {
+                Some(oldest) => { self.latest.remove(&oldest); },
+                None => return,
+            }
+        }
+
+        self.latest.insert(key.to_string(), event.clone());
+    }
+}
+
+/// A gateway session which can be resumed after a disconnect.
+struct GatewaySession {
+    user_id: i64,
+
+    /// When the session can no longer be resumed, `None` while connected.
+    expires_at: Option<i64>,
+}
+
+/// The live stats of a room on this instance.
+#[derive(Object)]
+pub struct RoomStats {
+    pub id: Uuid,
+
+    /// When the room was started as a unix timestamp in seconds.
+    pub started: i64,
+
+    /// The amount of connections currently receiving events.
+    pub receivers: usize,
+
+    /// The amount of sessions which are connected or can be resumed.
+    pub sessions: usize,
+
+    /// The amount of unique users connected.
+    pub members: usize,
+
+    /// The sequence number of the last event emitted to the room.
+    pub seq: u64,
+}
+
+impl RoomWrapper {
+    fn stats(&self, id: Uuid) -> RoomStats {
+        RoomStats {
+            id,
+            started: self.started,
+            receivers: self.messenger.receiver_count(),
+            sessions: self.sessions.len(),
+            members: self.members.len(),
+            seq: self.state.lock().unwrap().seq,
+        }
+    }
+}
+
+/// A user connected to a room.
+#[derive(Object, Clone)]
+pub struct Member {
+    pub id: JsSafeBigInt,
+    pub username: String,
+    pub avatar: Option<String>,
+
+    /// The amount of connections the user has to the room on this instance.
+    pub connections: usize,
+}
+
+impl Member {
+    fn new(user: &User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username.clone(),
+            avatar: user.avatar.clone(),
+            connections: 0,
+        }
+    }
+
+    /// The `MEMBER_JOIN` or `MEMBER_LEAVE` event for this member.
+    fn event(&self, type_: &str) -> Event {
+        Event::new(type_, json!({
+            "id": self.id.to_string(),
+            "username": self.username,
+            "avatar": self.avatar,
+        }))
+    }
+}
+
+/// Where an emitted event was delivered.
+pub enum Emitted {
+    /// Delivered to the room on this instance with the given amount of receivers.
+    Delivered(usize),
+
+    /// The room isn't active on this instance, the event was forwarded to the
+    /// other instances in the cluster.
+    Forwarded,
+}
+
+/// An instruction sent directly to a single connection.
+///
+/// Adjacently tagged as the `Send` event has a `type` field of its own.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ConnectionCommand {
+    /// Deliver the event to the connection.
+    Send(Event),
+
+    /// Close the connection sending a `CLOSE` event with the reason.
+    Disconnect {
+        reason: String,
+
+        /// The websocket close code, a normal closure if not set.
+        #[serde(default)]
+        code: Option<u16>,
+    },
+}
+
+/// A live connection in the user index.
+struct UserConnection {
+    /// The access token the connection was opened with.
+    token: String,
+    commands: Sender<ConnectionCommand>,
+}
+
+/// What a lagging receiver missed.
+#[derive(Debug)]
+pub struct Lagged {
+    /// The sequence number of the last event emitted to the room.
+    pub seq: u64,
+
+    /// The room events the receiver lost, `None` if some of them are no
+    /// longer buffered.
+    pub lost: Option<Vec<Arc<Event>>>,
+
+    /// The events which were still queued for the receiver.
+    pub queued: Vec<Arc<Event>>,
+}
+
+/// A new subscription to a room.
+pub struct Subscription {
+    pub receiver: broadcast::Receiver<Arc<Event>>,
+    pub session_id: Uuid,
+    pub commands: mpsc::Receiver<ConnectionCommand>,
+
+    /// Changes to the room's state made after subscribing.
+    pub updates: watch::Receiver<Option<Room>>,
+
+    /// The sequence number of the last event emitted to the room.
+    pub seq: u64,
+
+    /// The events missed since the resumed session's last sequence number,
+    /// `None` if a new session was started.
+    pub replay: Option<Vec<Arc<Event>>>,
+
+    /// The latest event of each coalesce key in the order they were
+    /// emitted, empty if the session was resumed.
+    pub latest: Vec<Arc<Event>>,
+
+    /// Removes the connection from the room once dropped.
+    pub guard: SubscriptionGuard,
+}
+
+/// Removes a connection from its room when dropped, so connections whose
+/// upgrade never completes are cleaned up as well.
+pub struct SubscriptionGuard {
+    emitter: EmitterManager,
+    room_id: Uuid,
+    session_id: Uuid,
+
+    /// The id of the connection in the user index.
+    connection_id: Uuid,
+    user_id: i64,
+}
+
+impl Drop for SubscriptionGuard {
+    fn drop(&mut self) {
+        self.emitter.disconnect(&self.room_id, (self.session_id, self.connection_id), self.user_id);
+    }
+}
+
 
 #[derive(Clone)]
 pub struct EmitterManager {
+    /// The id of this instance on the backplane.
+    node_id: Uuid,
+    config: Arc<EmitterConfig>,
     rooms: Arc<DashMap<Uuid, RoomWrapper>>,
+
+    /// Every live connection of each user across all rooms.
+    users: Arc<DashMap<i64, HashMap<Uuid, UserConnection>>>,
     shutdown_requests: Sender<Uuid>,
+    backplane: Arc<dyn Backplane>,
+    cluster_messages: UnboundedSender<ClusterMessage>,
+
+    /// The storage cache invalidated by other instances.
+    cache: Arc<CachedStorage>,
+
+    /// Set once the instance starts shutting down.
+    draining: Arc<AtomicBool>,
 }
 
 impl EmitterManager {
-    pub fn start() -> Self {
+    pub fn start(
+        config: EmitterConfig,
+        backplane: Arc<dyn Backplane>,
+        cache: Arc<CachedStorage>,
+    ) -> Self {
 
         let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let (cluster_tx, mut cluster_rx) = mpsc::unbounded_channel();
         let inst = Self {
+            node_id: Uuid::new_v4(),
+            config: Arc::new(config),
             rooms: Default::default(),
+            users: Default::default(),
             shutdown_requests: tx,
+            backplane: backplane.clone(),
+            cluster_messages: cluster_tx,
+            cache,
+            draining: Default::default(),
         };
         let manager = inst.clone();
 
@@ -47,50 +299,326 @@ impl EmitterManager {
         tokio::spawn(async move {
             while let Some(id) = rx.recv().await {
                 info!("Housekeeping - Closing room {}", &id);
+                METRICS.room_idle_closures.inc();
                 manager.close_room(&id, false)
             }
         });
 
+        // Messages are published from a single task so peers see them
+        // in the same order they were emitted.
+        let node_id = inst.node_id;
+        let publisher = backplane.clone();
+        tokio::spawn(async move {
+            while let Some(message) = cluster_rx.recv().await {
+                let envelope = Envelope {
+                    origin: node_id,
+                    message,
+                };
+
+                if let Err(e) = publisher.publish(&envelope).await {
+                    error!("Failed to publish message to the backplane: {}", e);
+                }
+            }
+        });
+
+        let manager = inst.clone();
+        let mut incoming = backplane.subscribe();
+        tokio::spawn(async move {
+            while let Some(envelope) = incoming.recv().await {
+                if envelope.origin == manager.node_id {
+                    continue;
+                }
+
+                manager.handle_cluster_message(envelope.message);
+            }
+        });
+
         inst
     }
 
+    pub fn config(&self) -> &EmitterConfig {
+        &self.config
+    }
+
+    /// Marks the instance as shutting down.
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops new connections and tells every connected client to
+    /// reconnect after `retry_after` seconds, waiting up to `timeout`
+    /// for their connections to close.
+    ///
+    /// Connections close themselves once they deliver the `RECONNECT`
+    /// event so it's received after every event emitted before it.
+    pub async fn drain(&self, retry_after: u64, timeout: Duration) {
+        self.start_draining();
+
+        let room_ids: Vec<Uuid> = self.rooms
+            .iter()
+            .map(|room| *room.key())
+            .collect();
+
+        for room_id in room_ids.iter() {
+            let event = Event::new("RECONNECT", json!({ "retry_after": retry_after }));
+            let _ = self.emit_local(room_id, event);
+        }
+
+        info!(
+            "Draining {} connections across {} rooms, waiting up to {:?}",
+            self.connection_count(),
+            room_ids.len(),
+            timeout,
+        );
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.connection_count() > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        match self.connection_count() {
+            0 => info!("Every connection was drained"),
+            remaining => warn!("Drain timed out with {} connections remaining", remaining),
+        }
+    }
+
+    /// The amount of live connections on this instance.
+    fn connection_count(&self) -> usize {
+        self.users
+            .iter()
+            .map(|connections| connections.len())
+            .sum()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Checks the housekeeping task closing idle rooms is still running.
+    pub fn is_housekeeping_alive(&self) -> bool {
+        !self.shutdown_requests.is_closed()
+    }
+
+    fn handle_cluster_message(&self, message: ClusterMessage) {
+        match message {
+            ClusterMessage::Emit { room_id, mut event, targets } => {
+                if self.rooms.contains_key(&room_id) {
+                    event.targets = targets.map(Arc::new);
+                    let _ = self.emit_local(&room_id, event);
+                }
+            },
+            ClusterMessage::CloseRoom { room_id, warn_clients } => {
+                self.close_room(&room_id, warn_clients);
+            },
+            ClusterMessage::UserCommand { user_id, command } => {
+                self.command_user_local(user_id, command);
+            },
+            ClusterMessage::InvalidateCache { invalidation } => {
+                self.cache.invalidate(&invalidation);
+            },
+            ClusterMessage::UpdateRoom { room } => {
+                self.cache.invalidate(&CacheInvalidation {
+                    room_ids: vec![room.id],
+                    ..Default::default()
+                });
+                self.update_room_local(room);
+            },
+            ClusterMessage::RevokeTokens { access_tokens, reason } => {
+                self.cache.invalidate(&CacheInvalidation {
+                    access_tokens: access_tokens.clone(),
+                    ..Default::default()
+                });
+                self.revoke_tokens_local(&access_tokens, &reason);
+            },
+        }
+    }
+
+    /// Drops the cached storage entries on every instance.
+    pub fn invalidate_cache(&self, invalidation: CacheInvalidation) {
+        self.cache.invalidate(&invalidation);
+
+        if self.backplane.is_clustered() {
+            let _ = self.cluster_messages.send(ClusterMessage::InvalidateCache { invalidation });
+        }
+    }
+
     pub fn close_room(&self, room_id: &Uuid, warn_clients: bool) {
         if warn_clients {
-            let _ = self.emit(room_id, Event { type_: "CLOSE".to_string(), data: Value::Null });
+            let _ = self.emit_local(room_id, Event::new("CLOSE", Value::Null));
         }
 
         self.rooms.remove(room_id);
     }
 
-    pub fn register_room(&self, room_id: Uuid) {
-        if self.rooms.contains_key(&room_id) {
+    /// Applies a change to the room's state on every instance.
+    ///
+    /// Inactive rooms are closed, otherwise every connection re-checks
+    /// its access to the room.
+    pub fn update_room(&self, room: Room) {
+        self.cache.invalidate(&CacheInvalidation {
+            room_ids: vec![room.id],
+            ..Default::default()
+        });
+
+        if self.backplane.is_clustered() {
+            let _ = self.cluster_messages.send(ClusterMessage::UpdateRoom {
+                room: room.clone(),
+            });
+        }
+
+        self.update_room_local(room);
+    }
+
+    fn update_room_local(&self, room: Room) {
+        if !room.active {
+            info!("Room {} was deactivated, closing", &room.id);
+            self.close_room(&room.id, true);
             return;
         }
 
+        if let Some(wrapper) = self.rooms.get(&room.id) {
+            wrapper.updates.send_replace(Some(room));
+        }
+    }
+
+    /// Closes the room on every instance.
+    ///
+    /// Returns `false` if the room is known not to exist.
+    pub fn force_close_room(&self, room_id: &Uuid, warn_clients: bool) -> bool {
+        let exists = self.rooms.contains_key(room_id);
+        let clustered = self.backplane.is_clustered();
+
+        if clustered {
+            let _ = self.cluster_messages.send(ClusterMessage::CloseRoom {
+                room_id: *room_id,
+                warn_clients,
+            });
+        }
+
+        if exists {
+            info!("Force closing room {}", room_id);
+            self.close_room(room_id, warn_clients);
+        }
+
+        exists || clustered
+    }
+
+    /// The stats of every active room on this instance.
+    pub fn list_rooms(&self) -> Vec<RoomStats> {
+        self.rooms
+            .iter()
+            .map(|room| room.stats(*room.key()))
+            .collect()
+    }
+
+    pub fn room_stats(&self, room_id: &Uuid) -> Option<RoomStats> {
+        self.rooms
+            .get(room_id)
+            .map(|room| room.stats(*room_id))
+    }
+
+    /// Works out which events a lagging receiver lost by draining the
+    /// events still queued for it, `None` if the room has been closed.
+    ///
+    /// Room events are only emitted while holding the room's lock, so
+    /// everything before the first event still queued was lost.
+    pub fn recover_lagged(
+        &self,
+        room_id: &Uuid,
+        last_seq: u64,
+        receiver: &mut broadcast::Receiver<Arc<Event>>,
+    ) -> Option<Lagged> {
+        let room = self.rooms.get(room_id)?;
+        let state = room.state.lock().unwrap();
+
+        let mut queued = Vec::new();
+        loop {
+            match receiver.try_recv() {
+                Ok(event) => queued.push(event),
+                // Only `PING`s are sent without the lock.
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+
+        let next_seq = queued
+            .iter()
+            .find_map(|event| event.seq)
+            .unwrap_or(state.seq + 1);
+
+        let lost = replay_after(&state, last_seq).map(|events| {
+            events
+                .into_iter()
+                .filter(|event| event.seq.map(|seq| seq < next_seq).unwrap_or(false))
+                .collect()
+        });
+
+        Some(Lagged {
+            seq: state.seq,
+            lost,
+            queued,
+        })
+    }
+
+    /// Starts the room on this instance if it isn't already active.
+    pub fn register_room(&self, room: &Room) {
+        // Started while holding the entry so concurrent first connections
+        // can't replace each other's room.
+        self.rooms
+            .entry(room.id)
+            .or_insert_with(|| self.start_room(room));
+    }
+
+    /// Creates the room's broadcast channel and starts its keep alive task.
+    fn start_room(&self, room: &Room) -> RoomWrapper {
+        let room_id = room.id;
+        let capacity = match room.broadcast_capacity.filter(|capacity| *capacity > 0) {
+            Some(capacity) if capacity as usize > self.config.max_broadcast_capacity => {
+                warn!(
+                    "Room {} has a broadcast capacity of {}, clamping it to {}",
+                    &room_id,
+                    capacity,
+                    self.config.max_broadcast_capacity,
+                );
+                self.config.max_broadcast_capacity
+            },
+            Some(capacity) => capacity as usize,
+            None => self.config.broadcast_capacity,
+        };
+
         let housekeeper = self.shutdown_requests.clone();
-        let sender = broadcast::channel(32).0;
+        let sender = broadcast::channel(capacity).0;
+        let sessions: Arc<DashMap<Uuid, GatewaySession>> = Default::default();
 
         let emitter = sender.clone();
+        let room_sessions = sessions.clone();
+        let keep_alive_ping = self.config.keep_alive_ping;
+        let max_interval_misses = self.config.max_interval_misses;
         let handle = tokio::spawn(async move {
-            let id = room_id.clone();
+            let id = room_id;
 
-            let mut interval = tokio::time::interval(Duration::from_secs(KEEP_ALIVE_PING));
+            let mut interval = tokio::time::interval(Duration::from_secs(keep_alive_ping));
 
             let mut intervals_elapsed: u64 = 0;
+            let mut ping_seq: u64 = 0;
 
             loop {
                 interval.tick().await;
-                let connections_alive = emitter.send(Event {
-                    type_: "".to_string(),
-                    data: Default::default()
-                }).is_ok();
+
+                let now = chrono::Utc::now().timestamp();
+                room_sessions.retain(|_, session| {
+                    session.expires_at.map(|expires_at| expires_at > now).unwrap_or(true)
+                });
+
+                ping_seq += 1;
+                let connections_alive = emitter.send(Arc::new(Event::ping(ping_seq))).is_ok();
 
                 if connections_alive {
                     if intervals_elapsed != 0 {
                         info!(
                             "Room {} has became active again! Got to {} seconds idle.",
                             &id,
-                            intervals_elapsed * KEEP_ALIVE_PING,
+                            intervals_elapsed * keep_alive_ping,
                         );
                     }
 
@@ -106,38 +634,745 @@ impl EmitterManager {
                     intervals_elapsed += 1;
                 }
 
-                if intervals_elapsed >= MAX_INTERVAL_MISSES {
+                if intervals_elapsed >= max_interval_misses {
                     info!("A room timeout has expired emitting shutdown for room {}", &id);
                     let _ = housekeeper.send(id).await;
                 }
             }
         });
 
-        let wrapped = RoomWrapper {
+        RoomWrapper {
             started: chrono::Utc::now().timestamp(),
             messenger: sender,
+            replay_size: capacity + self.config.replay_buffer_size - self.config.broadcast_capacity,
+            key_limit: self.config.coalesce_key_limit,
+            state: Default::default(),
+            sessions,
+            members: Default::default(),
+            updates: watch::channel(None).0,
             handle
+        }
+    }
+
+    /// Subscribes to the room's events.
+    ///
+    /// If `resume` refers to a session of the same user and every event
+    /// after the given sequence number is still buffered, the session is
+    /// resumed and the missed events are returned to be replayed,
+    /// otherwise a new session is started.
+    pub fn subscribe(
+        &self,
+        room_id: &Uuid,
+        user: &User,
+        token: &str,
+        resume: Option<(Uuid, u64)>,
+    ) -> Result<Subscription, ApiError> {
+        let user_id = *user.id;
+        let room = self.rooms
+            .get(room_id)
+            .ok_or(ApiError::RoomNotFound(*room_id))?;
+
+        // Held until the receiver is created so no event can be missed or
+        // delivered twice between the replay and the live events.
+        let state = room.state.lock().unwrap();
+
+        let resumed = resume.and_then(|(session_id, last_seq)| {
+            let mut session = room.sessions.get_mut(&session_id)?;
+            if session.user_id != user_id {
+                return None;
+            }
+
+            let replay = replay_after(&state, last_seq)?;
+            session.expires_at = None;
+
+            Some((session_id, replay))
+        });
+
+        let (session_id, replay) = match resumed {
+            Some((session_id, replay)) => (session_id, Some(replay)),
+            None => {
+                let session_id = Uuid::new_v4();
+                room.sessions.insert(session_id, GatewaySession {
+                    user_id,
+                    expires_at: None,
+                });
+
+                (session_id, None)
+            },
         };
 
-        self.rooms.insert(room_id, wrapped);
+        let joined = {
+            let mut member = room.members
+                .entry(user_id)
+                .or_insert_with(|| Member::new(user));
+            member.connections += 1;
+
+            (member.connections == 1).then(|| member.event("MEMBER_JOIN"))
+        };
+
+        let mut latest: Vec<Arc<Event>> = match replay {
+            Some(_) => Vec::new(),
+            None => state.latest.values().cloned().collect(),
+        };
+        latest.sort_by_key(|event| event.seq);
+
+        let connection_id = Uuid::new_v4();
+        let (tx, commands) = mpsc::channel(CONNECTION_COMMAND_BUFFER);
+        self.users
+            .entry(user_id)
+            .or_default()
+            .insert(connection_id, UserConnection {
+                token: token.to_string(),
+                commands: tx,
+            });
+
+        let subscription = Subscription {
+            receiver: room.messenger.subscribe(),
+            session_id,
+            commands,
+            updates: room.updates.subscribe(),
+            seq: state.seq,
+            replay,
+            latest,
+            guard: SubscriptionGuard {
+                emitter: self.clone(),
+                room_id: *room_id,
+                session_id,
+                connection_id,
+                user_id,
+            },
+        };
+
+        drop(state);
+        drop(room);
+
+        // Members are counted per instance, so presence is only sent to
+        // the connections on this instance.
+        if let Some(event) = joined {
+            let _ = self.emit_local(room_id, event);
+        }
+
+        Ok(subscription)
     }
 
-    pub fn get_subscriber(&self, room_id: &Uuid) -> broadcast::Receiver<Event> {
-        let room = self.rooms
+    /// Removes a connection from the room, its session can be resumed
+    /// until the resume timeout expires.
+    fn disconnect(&self, room_id: &Uuid, subscription: (Uuid, Uuid), user_id: i64) {
+        let (session_id, connection_id) = subscription;
+
+        self.users.remove_if_mut(&user_id, |_, connections| {
+            connections.remove(&connection_id);
+            connections.is_empty()
+        });
+
+        let room = match self.rooms.get(room_id) {
+            Some(room) => room,
+            None => return,
+        };
+
+        if let Some(mut session) = room.sessions.get_mut(&session_id) {
+            session.expires_at = Some(chrono::Utc::now().timestamp() + self.config.session_resume_timeout);
+        }
+
+        let left = room.members.remove_if_mut(&user_id, |_, member| {
+            member.connections = member.connections.saturating_sub(1);
+            member.connections == 0
+        });
+
+        drop(room);
+
+        if let Some((_, member)) = left {
+            let _ = self.emit_local(room_id, member.event("MEMBER_LEAVE"));
+        }
+    }
+
+    /// The users connected to the room on this instance.
+    pub fn room_members(&self, room_id: &Uuid) -> Option<Vec<Member>> {
+        self.rooms
             .get(room_id)
-            .unwrap();
+            .map(|room| {
+                room.members
+                    .iter()
+                    .map(|member| member.value().clone())
+                    .collect()
+            })
+    }
+
+    /// Sends the event to every connection of the user on every instance.
+    pub fn emit_to_user(&self, user_id: i64, event: Event) -> Emitted {
+        self.command_user(user_id, ConnectionCommand::Send(event))
+    }
+
+    /// Closes every connection of the user on every instance.
+    pub fn disconnect_user(&self, user_id: i64, reason: String) -> Emitted {
+        self.command_user(user_id, ConnectionCommand::Disconnect { reason, code: None })
+    }
+
+    /// Closes every connection opened with one of the access tokens on
+    /// every instance and drops the tokens from the storage cache.
+    pub fn revoke_tokens(&self, access_tokens: Vec<String>, reason: String) -> Emitted {
+        self.cache.invalidate(&CacheInvalidation {
+            access_tokens: access_tokens.clone(),
+            ..Default::default()
+        });
+
+        let amount = self.revoke_tokens_local(&access_tokens, &reason);
+
+        if !self.backplane.is_clustered() {
+            return Emitted::Delivered(amount)
+        }
+
+        let _ = self.cluster_messages.send(ClusterMessage::RevokeTokens {
+            access_tokens,
+            reason,
+        });
+
+        match amount {
+            0 => Emitted::Forwarded,
+            amount => Emitted::Delivered(amount),
+        }
+    }
+
+    fn revoke_tokens_local(&self, access_tokens: &[String], reason: &str) -> usize {
+        let command = ConnectionCommand::Disconnect {
+            reason: reason.to_string(),
+            code: Some(CLOSE_UNAUTHORIZED),
+        };
+
+        let access_tokens: HashSet<&str> = access_tokens
+            .iter()
+            .map(String::as_str)
+            .collect();
+
+        let mut amount = 0;
+        for connections in self.users.iter() {
+            for (connection_id, connection) in connections.iter() {
+                if !access_tokens.contains(connection.token.as_str()) {
+                    continue;
+                }
+
+                match connection.commands.try_send(command.clone()) {
+                    Ok(()) => amount += 1,
+                    Err(e) => warn!("Failed to revoke connection {} of user {}: {}", connection_id, connections.key(), e),
+                }
+            }
+        }
+
+        amount
+    }
+
+    fn command_user(&self, user_id: i64, command: ConnectionCommand) -> Emitted {
+        if self.backplane.is_clustered() {
+            let _ = self.cluster_messages.send(ClusterMessage::UserCommand {
+                user_id,
+                command: command.clone(),
+            });
+        }
 
-        room.messenger.subscribe()
+        match self.command_user_local(user_id, command) {
+            0 if self.backplane.is_clustered() => Emitted::Forwarded,
+            amount => Emitted::Delivered(amount),
+        }
     }
 
+    /// Sends the command to the user's connections on this instance
+    /// returning the amount of connections it was sent to.
+    fn command_user_local(&self, user_id: i64, command: ConnectionCommand) -> usize {
+        let connections = match self.users.get(&user_id) {
+            Some(connections) => connections,
+            None => return 0,
+        };
+
+        let mut amount = 0;
+        for (connection_id, connection) in connections.iter() {
+            match connection.commands.try_send(command.clone()) {
+                Ok(()) => amount += 1,
+                Err(e) => warn!("Failed to send command to connection {} of user {}: {}", connection_id, user_id, e),
+            }
+        }
+
+        amount
+    }
+
+    /// Emits the event to the room on every instance.
+    ///
+    /// When clustered the room may only exist on other instances so
+    /// failing to deliver it locally is not an error.
+    pub fn emit(&self, room_id: &Uuid, event: Event) -> Result<Emitted, ApiError> {
+        if !self.backplane.is_clustered() {
+            return self.emit_local(room_id, event).map(Emitted::Delivered);
+        }
+
+        let _ = self.cluster_messages.send(ClusterMessage::Emit {
+            room_id: *room_id,
+            targets: event.targets.as_deref().cloned(),
+            event: event.clone(),
+        });
+
+        match self.emit_local(room_id, event) {
+            Ok(amount) => Ok(Emitted::Delivered(amount)),
+            Err(e) => {
+                debug!("Event not delivered locally: {}", e);
+                Ok(Emitted::Forwarded)
+            },
+        }
+    }
+
+    /// Emits the event to the room on this instance returning the
+    /// amount of receivers it was sent to.
     #[instrument(name = "room-event", skip(self), level = "info")]
-    pub fn emit(&self, room_id: &Uuid, event: Event) -> Result<()> {
+    fn emit_local(&self, room_id: &Uuid, mut event: Event) -> Result<usize, ApiError> {
         if let Some(room) = self.rooms.get(room_id) {
-            let amount = room.messenger.send(event)?;
+            let mut state = room.state.lock().unwrap();
+
+            state.seq += 1;
+            event.set_seq(state.seq);
+
+            // Receivers only share the event, so it's encoded once here
+            // rather than by every connection.
+            event.pre_encode();
+            let event = Arc::new(event);
+
+            if state.replay.len() >= room.replay_size {
+                state.replay.pop_front();
+            }
+            state.replay.push_back(event.clone());
+
+            if let (Some(key), None) = (event.coalesce_key.as_ref(), event.targets.as_ref()) {
+                state.keep_latest(key, &event, room.key_limit);
+            }
+
+            let targeted = event.targets
+                .as_ref()
+                .map(|targets| targeted_connections(&room.members, targets));
+
+            // The event is still buffered for resuming sessions even
+            // if nobody is currently connected.
+            let sent = room.messenger.send(event).unwrap_or(0);
+            let amount = targeted.unwrap_or(sent);
+
+            METRICS.events_emitted.inc();
+            if amount == 0 {
+                METRICS.dropped(1, "no_receivers");
+            }
+
             info!("Broadcasting event to room {} with {} active receivers", room_id, amount);
-            Ok(())
+            Ok(amount)
         } else {
-            Err(anyhow!("no room exists with id {}", room_id))
+            Err(ApiError::RoomNotFound(*room_id))
+        }
+    }
+}
+
+/// The amount of connections the targeted event will be delivered to.
+fn targeted_connections(members: &DashMap<i64, Member>, targets: &Targets) -> usize {
+    members
+        .iter()
+        .filter(|member| targets.includes(*member.key()))
+        .map(|member| member.connections)
+        .sum()
+}
+
+/// Gets the buffered events after `last_seq`, `None` if some of them
+/// have already been dropped from the buffer.
+fn replay_after(state: &RoomState, last_seq: u64) -> Option<Vec<Arc<Event>>> {
+    if last_seq > state.seq {
+        return None;
+    }
+
+    let oldest = state.replay
+        .front()
+        .and_then(|event| event.seq)
+        .unwrap_or(state.seq + 1);
+
+    if last_seq + 1 < oldest {
+        return None;
+    }
+
+    let events = state.replay
+        .iter()
+        .filter(|event| event.seq.map(|seq| seq > last_seq).unwrap_or(false))
+        .cloned()
+        .collect();
+
+    Some(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backplane::LoopbackBackplane;
+    use crate::config::CacheConfig;
+    use crate::storage::MemoryStorage;
+
+    const USER_ID: i64 = 2;
+
+    fn emitter(config: EmitterConfig) -> EmitterManager {
+        emitter_on(config, LoopbackBackplane::default())
+    }
+
+    /// Starts an emitter sharing the backplane with any other emitters on it.
+    fn emitter_on(config: EmitterConfig, backplane: LoopbackBackplane) -> EmitterManager {
+        let storage = MemoryStorage::from_fixture_file("tests/fixtures/storage.json").unwrap();
+        let cache = CachedStorage::start(Arc::new(storage), &CacheConfig::default());
+
+        EmitterManager::start(config, Arc::new(backplane), cache)
+    }
+
+    /// Two emitters on the same backplane, only the second one has the room active.
+    fn cluster() -> (EmitterManager, EmitterManager, Room) {
+        let backplane = LoopbackBackplane::default();
+        let first = emitter_on(EmitterConfig::default(), backplane.clone());
+        let second = emitter_on(EmitterConfig::default(), backplane);
+
+        let room = room(4);
+        second.register_room(&room);
+
+        (first, second, room)
+    }
+
+    /// Waits for the next event which isn't a `PING`.
+    async fn next_broadcast(receiver: &mut broadcast::Receiver<Arc<Event>>) -> Arc<Event> {
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), receiver.recv())
+                .await
+                .expect("event not received")
+                .unwrap();
+
+            if event.type_ != "PING" {
+                return event
+            }
+        }
+    }
+
+    /// Waits for the next event of the given type, skipping any others.
+    async fn next_event(receiver: &mut broadcast::Receiver<Arc<Event>>, type_: &str) -> Arc<Event> {
+        loop {
+            let event = next_broadcast(receiver).await;
+            if event.type_ == type_ {
+                return event
+            }
+        }
+    }
+
+    async fn next_command(commands: &mut mpsc::Receiver<ConnectionCommand>) -> ConnectionCommand {
+        tokio::time::timeout(Duration::from_secs(1), commands.recv())
+            .await
+            .expect("command not received")
+            .unwrap()
+    }
+
+    fn user(id: i64) -> User {
+        User {
+            id: JsSafeBigInt(id),
+            access_servers: Default::default(),
+            avatar: None,
+            updated_on: 0,
+            username: format!("user-{}", id),
+        }
+    }
+
+    fn room(broadcast_capacity: i32) -> Room {
+        Room {
+            id: Uuid::new_v4(),
+            owner_id: JsSafeBigInt(1),
+            active: true,
+            active_playlist: None,
+            banner: None,
+            guild_id: None,
+            invite_only: false,
+            is_public: true,
+            playing_now: None,
+            title: "room".to_string(),
+            topic: None,
+            lag_policy: None,
+            broadcast_capacity: Some(broadcast_capacity),
+        }
+    }
+
+    fn keyed(type_: &str, key: &str) -> Event {
+        let mut event = Event::new(type_, json!({}));
+        event.coalesce_key = Some(key.to_string());
+        event
+    }
+
+    fn seqs(events: &[Arc<Event>]) -> Vec<u64> {
+        events.iter().filter_map(|event| event.seq).collect()
+    }
+
+    #[tokio::test]
+    async fn resumed_sessions_replay_missed_events() {
+        let emitter = emitter(EmitterConfig::default());
+        let room = room(4);
+        emitter.register_room(&room);
+
+        // MEMBER_JOIN is emitted as seq 1.
+        let subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        for _ in 0..3 {
+            emitter.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+        }
+
+        let session_id = subscription.session_id;
+        drop(subscription);
+
+        let mut resumed = emitter.subscribe(&room.id, &user(USER_ID), "token", Some((session_id, 2))).unwrap();
+        assert_eq!(resumed.session_id, session_id);
+        assert_eq!(resumed.seq, 5);
+        assert_eq!(seqs(resumed.replay.as_ref().unwrap()), vec![3, 4, 5]);
+        assert!(resumed.latest.is_empty());
+
+        // Replayed events are never received live as well, only the
+        // rejoin and anything after it.
+        emitter.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+        assert_eq!(resumed.receiver.try_recv().unwrap().type_, "MEMBER_JOIN");
+        assert_eq!(resumed.receiver.try_recv().unwrap().seq, Some(7));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_not_resumed_once_events_are_evicted() {
+        let emitter = emitter(EmitterConfig {
+            broadcast_capacity: 4,
+            replay_buffer_size: 5,
+            ..Default::default()
+        });
+        let room = room(4);
+        emitter.register_room(&room);
+
+        let subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        for _ in 0..4 {
+            emitter.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+        }
+
+        let session_id = subscription.session_id;
+        drop(subscription);
+
+        let resumed = emitter.subscribe(&room.id, &user(USER_ID), "token", Some((session_id, 0))).unwrap();
+        assert_ne!(resumed.session_id, session_id);
+        assert!(resumed.replay.is_none());
+    }
+
+    #[tokio::test]
+    async fn sessions_of_other_users_are_not_resumed() {
+        let emitter = emitter(EmitterConfig::default());
+        let room = room(4);
+        emitter.register_room(&room);
+
+        let subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        let resumed = emitter
+            .subscribe(&room.id, &user(USER_ID + 1), "token", Some((subscription.session_id, 0)))
+            .unwrap();
+
+        assert_ne!(resumed.session_id, subscription.session_id);
+        assert!(resumed.replay.is_none());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_guard_removes_the_connection() {
+        let emitter = emitter(EmitterConfig::default());
+        let room = room(4);
+        emitter.register_room(&room);
+
+        let subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        assert_eq!(emitter.room_members(&room.id).unwrap().len(), 1);
+        assert!(emitter.users.contains_key(&USER_ID));
+
+        drop(subscription);
+        assert!(emitter.room_members(&room.id).unwrap().is_empty());
+        assert!(!emitter.users.contains_key(&USER_ID));
+
+        let state = emitter.rooms.get(&room.id).unwrap();
+        let state = state.state.lock().unwrap();
+        let types: Vec<&str> = state.replay.iter().map(|event| event.type_.as_str()).collect();
+        assert_eq!(types, vec!["MEMBER_JOIN", "MEMBER_LEAVE"]);
+    }
+
+    #[tokio::test]
+    async fn recover_lagged_splits_lost_and_queued_events() {
+        let emitter = emitter(EmitterConfig {
+            broadcast_capacity: 4,
+            replay_buffer_size: 16,
+            ..Default::default()
+        });
+        let room = room(4);
+        emitter.register_room(&room);
+
+        let mut subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        for _ in 0..10 {
+            emitter.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
         }
+
+        let lagged = emitter.recover_lagged(&room.id, 1, &mut subscription.receiver).unwrap();
+        assert_eq!(lagged.seq, 11);
+        assert_eq!(seqs(lagged.lost.as_ref().unwrap()), vec![2, 3, 4, 5, 6, 7]);
+        assert_eq!(seqs(&lagged.queued), vec![8, 9, 10, 11]);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn recover_lagged_reports_evicted_events() {
+        let emitter = emitter(EmitterConfig {
+            broadcast_capacity: 4,
+            replay_buffer_size: 5,
+            ..Default::default()
+        });
+        let room = room(4);
+        emitter.register_room(&room);
+
+        let mut subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        for _ in 0..10 {
+            emitter.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+        }
+
+        let lagged = emitter.recover_lagged(&room.id, 1, &mut subscription.receiver).unwrap();
+        assert!(lagged.lost.is_none());
+        assert_eq!(seqs(&lagged.queued), vec![8, 9, 10, 11]);
+    }
+
+    #[tokio::test]
+    async fn rooms_with_a_larger_capacity_still_recover_lost_events() {
+        let emitter = emitter(EmitterConfig {
+            broadcast_capacity: 4,
+            replay_buffer_size: 8,
+            ..Default::default()
+        });
+        let room = room(16);
+        emitter.register_room(&room);
+
+        let mut subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        for _ in 0..20 {
+            emitter.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+        }
+
+        let lagged = emitter.recover_lagged(&room.id, 1, &mut subscription.receiver).unwrap();
+        assert_eq!(seqs(lagged.lost.as_ref().unwrap()), vec![2, 3, 4, 5]);
+        assert_eq!(lagged.queued.len(), 16);
+    }
+
+    #[tokio::test]
+    async fn room_capacities_are_clamped() {
+        let emitter = emitter(EmitterConfig::default());
+        let room = room(i32::MAX);
+        emitter.register_room(&room);
+
+        let wrapper = emitter.rooms.get(&room.id).unwrap();
+        assert_eq!(wrapper.replay_size, 1024 + 128 - 32);
+    }
+
+    #[tokio::test]
+    async fn new_sessions_get_the_latest_event_of_each_key() {
+        let emitter = emitter(EmitterConfig::default());
+        let room = room(4);
+        emitter.register_room(&room);
+
+        emitter.emit(&room.id, keyed("PROGRESS", "progress")).unwrap();
+        emitter.emit(&room.id, keyed("TRACK", "track")).unwrap();
+        emitter.emit(&room.id, keyed("PROGRESS", "progress")).unwrap();
+
+        let mut targeted = keyed("VOLUME", "volume");
+        targeted.targets = Some(Arc::new(Targets::default()));
+        emitter.emit(&room.id, targeted).unwrap();
+
+        let subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        assert_eq!(seqs(&subscription.latest), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    async fn coalesce_keys_are_capped() {
+        let emitter = emitter(EmitterConfig {
+            coalesce_key_limit: 2,
+            ..Default::default()
+        });
+        let room = room(4);
+        emitter.register_room(&room);
+
+        emitter.emit(&room.id, keyed("A", "a")).unwrap();
+        emitter.emit(&room.id, keyed("B", "b")).unwrap();
+        emitter.emit(&room.id, keyed("A", "a")).unwrap();
+        emitter.emit(&room.id, keyed("C", "c")).unwrap();
+
+        let subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        let keys: Vec<&str> = subscription.latest
+            .iter()
+            .filter_map(|event| event.coalesce_key.as_deref())
+            .collect();
+        assert_eq!(keys, vec!["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn room_events_reach_other_instances() {
+        let (first, second, room) = cluster();
+        let mut subscription = second.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+
+        let emitted = first.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+        assert!(matches!(emitted, Emitted::Forwarded));
+
+        let event = next_event(&mut subscription.receiver, "TRACK").await;
+        assert_eq!(event.seq, Some(2));
+    }
+
+    #[tokio::test]
+    async fn user_commands_reach_other_instances() {
+        let (first, second, room) = cluster();
+        let mut subscription = second.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+
+        let emitted = first.emit_to_user(USER_ID, Event::new("HELLO", json!({})));
+        assert!(matches!(emitted, Emitted::Forwarded));
+
+        match next_command(&mut subscription.commands).await {
+            ConnectionCommand::Send(event) => assert_eq!(event.type_, "HELLO"),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn closed_rooms_close_on_other_instances() {
+        let (first, second, room) = cluster();
+        let mut subscription = second.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+
+        assert!(first.force_close_room(&room.id, true));
+
+        next_event(&mut subscription.receiver, "CLOSE").await;
+        assert!(second.room_stats(&room.id).is_none());
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_disconnect_on_other_instances() {
+        let (first, second, room) = cluster();
+        let mut subscription = second.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+
+        first.revoke_tokens(vec!["token".to_string()], "revoked".to_string());
+
+        match next_command(&mut subscription.commands).await {
+            ConnectionCommand::Disconnect { code, .. } => assert_eq!(code, Some(CLOSE_UNAUTHORIZED)),
+            command => panic!("unexpected command {:?}", command),
+        }
+    }
+
+    #[tokio::test]
+    async fn presence_stays_on_its_instance() {
+        let (first, second, room) = cluster();
+        first.register_room(&room);
+
+        let mut subscription = first.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        drop(second.subscribe(&room.id, &user(USER_ID), "token", None).unwrap());
+
+        // Forwarded after the second instance's presence would have been.
+        second.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+
+        assert_eq!(next_broadcast(&mut subscription.receiver).await.type_, "MEMBER_JOIN");
+        assert_eq!(next_broadcast(&mut subscription.receiver).await.type_, "TRACK");
+    }
+
+    #[tokio::test]
+    async fn registering_an_active_room_keeps_it() {
+        let emitter = emitter(EmitterConfig::default());
+        let room = room(4);
+        emitter.register_room(&room);
+
+        let mut subscription = emitter.subscribe(&room.id, &user(USER_ID), "token", None).unwrap();
+        emitter.register_room(&room);
+
+        emitter.emit(&room.id, Event::new("TRACK", json!({}))).unwrap();
+        assert_eq!(next_event(&mut subscription.receiver, "TRACK").await.seq, Some(2));
+        assert_eq!(emitter.room_members(&room.id).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt::{Debug, Formatter};
+use std::io::Write;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Result;
+use bytes::Bytes;
+use poem::web::websocket::Message;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+
+/// How events are encoded in the frames sent to a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// JSON in binary frames, the default.
+    #[default]
+    Json,
+
+    /// JSON in text frames.
+    JsonText,
+    MsgPack,
+    Cbor,
+}
+
+/// Every supported encoding.
+const ENCODINGS: [Encoding; 4] = [
+    Encoding::Json,
+    Encoding::JsonText,
+    Encoding::MsgPack,
+    Encoding::Cbor,
+];
+
+impl Encoding {
+    /// Parses the value of the `encoding` query param.
+    pub fn from_name(name: &str) -> Option<Self> {
+        ENCODINGS.iter().copied().find(|encoding| encoding.name() == name)
+    }
+
+    /// Finds the encoding of a `Sec-WebSocket-Protocol`.
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        ENCODINGS.iter().copied().find(|encoding| encoding.protocol() == protocol)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::JsonText => "json-text",
+            Self::MsgPack => "msgpack",
+            Self::Cbor => "cbor",
+        }
+    }
+
+    /// The websocket sub-protocol selecting this encoding.
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Self::Json => "socketeer.json",
+            Self::JsonText => "socketeer.json-text",
+            Self::MsgPack => "socketeer.msgpack",
+            Self::Cbor => "socketeer.cbor",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Self::Json => 0,
+            Self::JsonText => 1,
+            Self::MsgPack => 2,
+            Self::Cbor => 3,
+        }
+    }
+
+    /// Encodes the value into a frame.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Frame> {
+        let frame = match self {
+            Self::Json => Frame::Binary(serde_json::to_vec(value)?.into()),
+            Self::JsonText => Frame::Text(serde_json::to_string(value)?.into()),
+            Self::MsgPack => Frame::Binary(rmp_serde::to_vec_named(value)?.into()),
+            Self::Cbor => Frame::Binary(serde_cbor::to_vec(value)?.into()),
+        };
+
+        Ok(frame)
+    }
+
+    /// Decodes the payload of a binary frame.
+    pub fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        let value = match self {
+            Self::Json | Self::JsonText => serde_json::from_slice(payload)?,
+            Self::MsgPack => rmp_serde::from_slice(payload)?,
+            Self::Cbor => serde_cbor::from_slice(payload)?,
+        };
+
+        Ok(value)
+    }
+}
+
+
+/// How frames are compressed after being encoded.
+///
+/// Every frame is compressed on its own rather than as part of a stream
+/// so the compressed bytes can be shared between connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zlib,
+    Zstd,
+}
+
+/// Every supported compression.
+const COMPRESSIONS: [Compression; 3] = [
+    Compression::None,
+    Compression::Zlib,
+    Compression::Zstd,
+];
+
+/// The zstd compression level, `0` uses zstd's default.
+const ZSTD_LEVEL: i32 = 0;
+
+impl Compression {
+    /// Parses the value of the `compression` query param.
+    pub fn from_name(name: &str) -> Option<Self> {
+        COMPRESSIONS.iter().copied().find(|compression| compression.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Zlib => "zlib",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Zlib => 1,
+            Self::Zstd => 2,
+        }
+    }
+
+    /// Compresses the payload of a frame.
+    pub fn compress(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self {
+            Self::None => payload.to_vec(),
+            Self::Zlib => {
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(payload)?;
+                encoder.finish()?
+            },
+            Self::Zstd => zstd::encode_all(payload, ZSTD_LEVEL)?,
+        };
+
+        Ok(compressed)
+    }
+}
+
+
+/// An encoded frame which is cheap to share between connections.
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Text(Arc<str>),
+    Binary(Bytes),
+}
+
+impl Frame {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Text(text) => text.as_bytes(),
+            Self::Binary(data) => data,
+        }
+    }
+
+    /// The message writing the frame to a socket.
+    ///
+    /// Messages own their payload so this is the only copy made for each
+    /// connection.
+    pub fn to_message(&self) -> Message {
+        match self {
+            Self::Text(text) => Message::Text(text.to_string()),
+            Self::Binary(data) => Message::Binary(data.to_vec()),
+        }
+    }
+}
+
+
+/// The frames an event has been encoded into, shared between every clone
+/// of the event so it's only encoded and compressed once per format no
+/// matter how many connections it's delivered to.
+#[derive(Clone, Default)]
+pub struct EncodedFrames(Arc<[OnceLock<Frame>; ENCODINGS.len() * COMPRESSIONS.len()]>);
+
+impl EncodedFrames {
+    /// Gets the frame for the encoding and compression, encoding and
+    /// compressing the value if needed.
+    ///
+    /// Compressed frames are always binary frames. Connections racing to
+    /// encode the same format may both encode it, only the first frame is kept.
+    pub fn get_or_encode<T: Serialize>(
+        &self,
+        encoding: Encoding,
+        compression: Compression,
+        value: &T,
+    ) -> Result<Frame> {
+        let slot = &self.0[encoding.index() * COMPRESSIONS.len() + compression.index()];
+        if let Some(frame) = slot.get() {
+            return Ok(frame.clone())
+        }
+
+        let frame = if compression == Compression::None {
+            encoding.encode(value)?
+        } else {
+            let frame = self.get_or_encode(encoding, Compression::None, value)?;
+            Frame::Binary(compression.compress(frame.as_bytes())?.into())
+        };
+
+        Ok(slot.get_or_init(|| frame).clone())
+    }
+}
+
+impl Debug for EncodedFrames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EncodedFrames")
+    }
+}
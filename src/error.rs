@@ -0,0 +1,96 @@
+use std::fmt::{Display, Formatter};
+
+use poem::error::ResponseError;
+use poem::http::StatusCode;
+use uuid::Uuid;
+
+use crate::authz::Denial;
+use crate::utils::Detail;
+
+
+/// An error returned to REST and gateway clients.
+///
+/// Errors are rendered as a JSON [`Detail`] body by the logging middleware.
+#[derive(Debug)]
+pub enum ApiError {
+    /// The request is invalid.
+    BadRequest(String),
+
+    /// The access token is missing or invalid.
+    Unauthorized,
+
+    /// The user isn't allowed to join the room.
+    Denied(Denial),
+
+    RoomNotFound(Uuid),
+
+    UserNotConnected(i64),
+
+    /// The server is shutting down and not accepting new connections.
+    Draining,
+
+    /// Something went wrong on our end, the details are only logged.
+    Internal(anyhow::Error),
+}
+
+impl ApiError {
+    /// A stable identifier for the error clients can match on.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "BAD_REQUEST",
+            Self::Unauthorized => "UNAUTHORIZED",
+            Self::Denied(denial) => denial.code(),
+            Self::RoomNotFound(_) => "ROOM_NOT_FOUND",
+            Self::UserNotConnected(_) => "USER_NOT_CONNECTED",
+            Self::Draining => "DRAINING",
+            Self::Internal(_) => "INTERNAL",
+        }
+    }
+
+    /// The body sent to the client.
+    pub fn detail(&self) -> Detail {
+        let detail = match self {
+            Self::Internal(_) => "internal server error".to_string(),
+            other => other.to_string(),
+        };
+
+        Detail::with_reason(detail, self.reason())
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(msg) => f.write_str(msg),
+            Self::Unauthorized => f.write_str("unauthorized user"),
+            Self::Denied(denial) => write!(f, "{}", denial),
+            Self::RoomNotFound(room_id) => write!(f, "no room exists with id {}", room_id),
+            Self::UserNotConnected(user_id) => write!(f, "user {} is not connected", user_id),
+            Self::Draining => f.write_str("server is shutting down, reconnect to another instance"),
+            Self::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Denied(Denial::RoomInactive) => StatusCode::BAD_REQUEST,
+            Self::Denied(_) => StatusCode::FORBIDDEN,
+            Self::RoomNotFound(_) => StatusCode::NOT_FOUND,
+            Self::UserNotConnected(_) => StatusCode::NOT_FOUND,
+            Self::Draining => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::Internal(e)
+    }
+}
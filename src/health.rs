@@ -0,0 +1,96 @@
+use std::time::Duration;
+
+use poem::{handler, IntoResponse, Response};
+use poem::http::StatusCode;
+use poem::web::{Data, Json};
+use serde::Serialize;
+
+use crate::emitter::EmitterManager;
+use crate::storage::DynStorage;
+
+
+/// How long the storage has to answer before it's considered unavailable.
+const STORAGE_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct Readiness {
+    /// `ready`, `draining` or `unavailable`.
+    status: &'static str,
+    draining: bool,
+    checks: Checks,
+}
+
+#[derive(Serialize)]
+struct Checks {
+    storage: Check,
+    housekeeping: Check,
+}
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+
+    /// Why the check failed.
+    detail: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Self { ok: true, detail: None }
+    }
+
+    fn failed(detail: impl Into<String>) -> Self {
+        Self { ok: false, detail: Some(detail.into()) }
+    }
+}
+
+/// Always succeeds while the process is serving requests.
+#[handler]
+pub fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Succeeds while the instance can accept new connections, i.e. the
+/// storage is reachable, idle rooms are being closed and the instance
+/// isn't shutting down.
+#[handler]
+pub async fn readyz(
+    storage: Data<&DynStorage>,
+    emitter: Data<&EmitterManager>,
+) -> Response {
+    let storage = match tokio::time::timeout(STORAGE_PING_TIMEOUT, storage.ping()).await {
+        Ok(Ok(())) => Check::ok(),
+        Ok(Err(e)) => {
+            warn!("Readiness check failed to ping storage: {}", e);
+            Check::failed(format!("storage ping failed: {}", e))
+        },
+        Err(_) => {
+            warn!("Readiness check timed out pinging storage");
+            Check::failed("storage ping timed out")
+        },
+    };
+
+    let housekeeping = if emitter.is_housekeeping_alive() {
+        Check::ok()
+    } else {
+        Check::failed("housekeeping task has stopped")
+    };
+
+    let draining = emitter.is_draining();
+    let healthy = storage.ok && housekeeping.ok;
+    let (status, code) = match (draining, healthy) {
+        (true, _) => ("draining", StatusCode::SERVICE_UNAVAILABLE),
+        (false, true) => ("ready", StatusCode::OK),
+        (false, false) => ("unavailable", StatusCode::SERVICE_UNAVAILABLE),
+    };
+
+    let readiness = Readiness {
+        status,
+        draining,
+        checks: Checks { storage, housekeeping },
+    };
+
+    Json(readiness)
+        .with_status(code)
+        .into_response()
+}
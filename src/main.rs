@@ -4,6 +4,15 @@ mod utils;
 mod models;
 mod ws;
 mod emitter;
+mod backplane;
+mod config;
+mod storage;
+mod migrations;
+mod authz;
+mod error;
+mod metrics;
+mod health;
+mod encoding;
 
 #[macro_use]
 extern crate tracing;
@@ -16,13 +25,18 @@ use std::sync::Arc;
 use std::time::Duration;
 use poem::{Endpoint, EndpointExt, IntoResponse, Request, Response, Result, Route, Server};
 use poem::listener::TcpListener;
-use poem::http::Method;
+use poem::http::{Method, StatusCode};
 use poem_openapi::OpenApiService;
+use poem_openapi::payload::Json;
 
-use concread::arcache::{ARCache, ARCacheBuilder};
 use poem::middleware::Cors;
 use tokio::time::Instant;
+use crate::backplane::{Backplane, LoopbackBackplane, RedisBackplane};
+use crate::config::{Config, StorageBackend};
 use crate::emitter::EmitterManager;
+use crate::error::ApiError;
+use crate::utils::Detail;
+use crate::storage::{CachedStorage, DynStorage, MemoryStorage, ScyllaStorage};
 
 
 #[tokio::main]
@@ -32,11 +46,46 @@ async fn main() -> anyhow::Result<()> {
     }
     tracing_subscriber::fmt::init();
 
-    let session = db::connect("127.0.0.1:9042").await?;
-    let cache: ARCache<String, String> = ARCacheBuilder::new()
-        .set_size(1024, 10)
-        .build()
-        .unwrap();
+    let config = Config::load()?;
+
+    match std::env::args().nth(1).as_deref() {
+        None | Some("serve") => serve(config).await,
+        Some("migrate") => {
+            let session = db::connect_cluster(&config.database).await?;
+            migrations::run(&session, &config.database).await
+        },
+        Some(other) => Err(anyhow::anyhow!("unknown command {}, expected `serve` or `migrate`", other)),
+    }
+}
+
+async fn serve(config: Config) -> anyhow::Result<()> {
+    let storage: DynStorage = match config.storage.backend {
+        StorageBackend::Scylla => {
+            let session = db::connect(&config.database).await?;
+            Arc::new(ScyllaStorage::from(session))
+        },
+        StorageBackend::Memory => match config.storage.fixture.as_ref() {
+            Some(path) => {
+                info!("Using in-memory storage seeded from {}", path);
+                Arc::new(MemoryStorage::from_fixture_file(path)?)
+            },
+            None => {
+                warn!("Using empty in-memory storage, no users or rooms exist");
+                Arc::new(MemoryStorage::default())
+            },
+        },
+    };
+
+    let backplane: Arc<dyn Backplane> = match config.backplane.redis_url.as_ref() {
+        Some(url) => {
+            info!("Connecting to redis backplane at {}", url);
+            Arc::new(RedisBackplane::connect(url).await?)
+        },
+        None => Arc::new(LoopbackBackplane::default()),
+    };
+    let cache = CachedStorage::start(storage, &config.cache);
+    let storage: DynStorage = cache.clone();
+    let emitter = EmitterManager::start(config.emitter.clone(), backplane, cache.clone());
 
     let api_service = OpenApiService::new(
         rest::RestApi,
@@ -44,7 +93,7 @@ async fn main() -> anyhow::Result<()> {
         "1.0.0"
         )
         .description("The Spooderfy socketeer rtc system.")
-        .server("http://127.0.0.1:8800/api/v0");
+        .server(format!("{}/api/v0", config.server.public_url.trim_end_matches('/')));
 
     let ui = api_service.redoc();
     let spec = api_service.spec();
@@ -54,22 +103,30 @@ async fn main() -> anyhow::Result<()> {
         .nest("/ui", ui)
         .at("/ws/v0/gateway", ws::gateway)
         .at("/spec", poem::endpoint::make_sync(move |_| spec.clone()))
+        .at("/metrics", metrics::metrics)
+        .at("/healthz", health::healthz)
+        .at("/readyz", health::readyz)
         .with(
             Cors::new()
-                .allow_origins(["http://127.0.0.1:3000", "http://localhost:3000"])
+                .allow_origins(config.server.cors_origins.clone())
                 .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::PUT, Method::OPTIONS])
                 .allow_credentials(true)
         )
         .around(log)
-        .data(session)
-        .data(EmitterManager::start())
-        .data(Arc::new(cache));
+        .data(storage)
+        .data(emitter.clone())
+        .data(cache);
 
-    Server::new(TcpListener::bind("127.0.0.1:8800"))
+    Server::new(TcpListener::bind(config.server.bind.clone()))
         .run_with_graceful_shutdown(
             app,
             async move {
-                let _ = tokio::signal::ctrl_c().await;
+                shutdown_signal().await;
+                info!("Shutting down, draining connections");
+                emitter.drain(
+                    config.server.reconnect_retry_after,
+                    Duration::from_secs(config.server.drain_timeout),
+                ).await;
             },
             Some(Duration::from_secs(2)),
         )
@@ -78,6 +135,31 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Resolves once the process receives SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                return;
+            },
+        };
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 async fn log<E: Endpoint>(next: E, req: Request) -> Result<Response> {
     let method = req.method().clone();
     let path = req.uri().clone();
@@ -102,10 +184,9 @@ async fn log<E: Endpoint>(next: E, req: Request) -> Result<Response> {
             Ok(resp)
         },
         Err(e) => {
+            let resp = error_response(&e);
 
-            let resp = e.as_response();
-
-            if resp.status().as_u16() >= 500 {
+            if resp.status() == StatusCode::INTERNAL_SERVER_ERROR {
                 error!("{}", &e);
             }
 
@@ -118,7 +199,21 @@ async fn log<E: Endpoint>(next: E, req: Request) -> Result<Response> {
                 path.path(),
             );
 
-            Err(e)
+            Ok(resp)
         }
     }
 }
+
+/// Renders the error as a JSON `Detail` body, the details of internal
+/// errors are only logged.
+fn error_response(e: &poem::Error) -> Response {
+    let detail = match e.downcast_ref::<ApiError>() {
+        Some(e) => e.detail(),
+        None if e.status().is_server_error() => Detail::from("internal server error".to_string()),
+        None => Detail::from(e.to_string()),
+    };
+
+    Json(detail)
+        .with_status(e.status())
+        .into_response()
+}
@@ -0,0 +1,185 @@
+use poem::{handler, Response};
+use poem::web::Data;
+use poem::http::StatusCode;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder,
+    Histogram,
+    HistogramOpts,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGauge,
+    IntGaugeVec,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+
+use crate::emitter::EmitterManager;
+
+
+/// The histogram buckets of query latencies in seconds.
+const LATENCY_BOUNDARIES: &[f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// The histogram buckets of heartbeat round trips in seconds.
+const RTT_BOUNDARIES: &[f64] = &[0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+/// The instruments recorded across the service.
+pub struct Metrics {
+    /// Events emitted to rooms on this instance.
+    pub events_emitted: IntCounter,
+
+    /// Events written to connections, including replayed and direct events.
+    pub events_delivered: IntCounter,
+
+    /// Events which never reached a connection, by `reason`.
+    events_dropped: IntCounterVec,
+
+    /// Times a connection fell behind its room's broadcast channel.
+    pub lag_events: IntCounter,
+
+    /// Connections closed for lagging behind too often.
+    pub lag_kicks: IntCounter,
+
+    /// Rooms closed after being idle for too long.
+    pub room_idle_closures: IntCounter,
+
+    /// The duration of prepared Scylla queries in seconds, by `outcome`.
+    query_duration: HistogramVec,
+
+    /// The round trip of acknowledged heartbeats in seconds.
+    pub heartbeat_rtt: Histogram,
+
+    /// Storage cache lookups, by `cache`.
+    cache_hits: IntCounterVec,
+    cache_misses: IntCounterVec,
+
+    /// Set from the emitter on every scrape so closed rooms are removed.
+    active_rooms: IntGauge,
+    room_connections: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Self {
+            events_emitted: register(IntCounter::new(
+                "socketeer_events_emitted_total",
+                "Events emitted to rooms on this instance.",
+            )),
+            events_delivered: register(IntCounter::new(
+                "socketeer_events_delivered_total",
+                "Events written to connections.",
+            )),
+            events_dropped: register(IntCounterVec::new(
+                Opts::new("socketeer_events_dropped_total", "Events which never reached a connection."),
+                &["reason"],
+            )),
+            lag_events: register(IntCounter::new(
+                "socketeer_lag_events_total",
+                "Times a connection fell behind its room.",
+            )),
+            lag_kicks: register(IntCounter::new(
+                "socketeer_lag_kicks_total",
+                "Connections closed for lagging behind.",
+            )),
+            room_idle_closures: register(IntCounter::new(
+                "socketeer_room_idle_closures_total",
+                "Rooms closed after their idle timeout expired.",
+            )),
+            query_duration: register(HistogramVec::new(
+                HistogramOpts::new(
+                    "socketeer_scylla_query_duration_seconds",
+                    "The duration of prepared Scylla queries.",
+                ).buckets(LATENCY_BOUNDARIES.to_vec()),
+                &["outcome"],
+            )),
+            heartbeat_rtt: register(Histogram::with_opts(
+                HistogramOpts::new(
+                    "socketeer_heartbeat_rtt_seconds",
+                    "The round trip of acknowledged heartbeats.",
+                ).buckets(RTT_BOUNDARIES.to_vec()),
+            )),
+            cache_hits: register(IntCounterVec::new(
+                Opts::new("socketeer_cache_hits_total", "Storage lookups answered by the cache."),
+                &["cache"],
+            )),
+            cache_misses: register(IntCounterVec::new(
+                Opts::new("socketeer_cache_misses_total", "Storage lookups which missed the cache."),
+                &["cache"],
+            )),
+            active_rooms: register(IntGauge::new(
+                "socketeer_active_rooms",
+                "Rooms active on this instance.",
+            )),
+            room_connections: register(IntGaugeVec::new(
+                Opts::new("socketeer_room_connections", "Connections to each room on this instance."),
+                &["room_id"],
+            )),
+        }
+    }
+
+    /// Counts events which were dropped for the given reason.
+    pub fn dropped(&self, amount: u64, reason: &'static str) {
+        self.events_dropped.with_label_values(&[reason]).inc_by(amount);
+    }
+
+    /// Records the duration of a prepared query, `outcome` is `ok` or `error`.
+    pub fn query(&self, seconds: f64, outcome: &'static str) {
+        self.query_duration.with_label_values(&[outcome]).observe(seconds);
+    }
+
+    /// The hit and miss counters of the storage cache with the given name.
+    pub fn cache(&self, cache: &'static str) -> (IntCounter, IntCounter) {
+        (
+            self.cache_hits.with_label_values(&[cache]),
+            self.cache_misses.with_label_values(&[cache]),
+        )
+    }
+
+    fn refresh_rooms(&self, emitter: &EmitterManager) {
+        let rooms = emitter.list_rooms();
+
+        self.active_rooms.set(rooms.len() as i64);
+        self.room_connections.reset();
+        for room in rooms {
+            self.room_connections
+                .with_label_values(&[&room.id.to_string()])
+                .set(room.receivers as i64);
+        }
+    }
+}
+
+/// Registers the metric, the names are fixed so this can only fail on a
+/// programming error.
+fn register<T: Collector + Clone + 'static>(metric: prometheus::Result<T>) -> T {
+    let metric = metric.expect("invalid metric");
+    REGISTRY.register(Box::new(metric.clone())).expect("metric registered twice");
+    metric
+}
+
+/// Renders every metric in the Prometheus text format.
+#[handler]
+pub fn metrics(emitter: Data<&EmitterManager>) -> Response {
+    METRICS.refresh_rooms(&emitter);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(e) = encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        error!("Failed to encode metrics: {}", e);
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .finish();
+    }
+
+    Response::builder()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
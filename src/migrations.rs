@@ -0,0 +1,146 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Result};
+use scylla::IntoTypedRows;
+use scylla::frame::value::Timestamp;
+
+use crate::config::DatabaseConfig;
+
+/// A versioned set of CQL statements separated by `;`.
+struct Migration {
+    version: i32,
+    name: &'static str,
+    cql: &'static str,
+}
+
+/// Every migration in the order they are applied, versions must only
+/// ever be appended.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_users",
+        cql: include_str!("./scripts/migrations/0001_create_users.cql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_access_tokens",
+        cql: include_str!("./scripts/migrations/0002_create_access_tokens.cql"),
+    },
+    Migration {
+        version: 3,
+        name: "create_rooms",
+        cql: include_str!("./scripts/migrations/0003_create_rooms.cql"),
+    },
+    Migration {
+        version: 4,
+        name: "create_room_invites",
+        cql: include_str!("./scripts/migrations/0004_create_room_invites.cql"),
+    },
+    // `ALTER TABLE` can't be made idempotent, so each one is its own
+    // migration in case a later statement fails.
+    Migration {
+        version: 5,
+        name: "add_room_lag_policy",
+        cql: include_str!("./scripts/migrations/0005_add_room_lag_policy.cql"),
+    },
+    Migration {
+        version: 6,
+        name: "add_room_broadcast_capacity",
+        cql: include_str!("./scripts/migrations/0006_add_room_broadcast_capacity.cql"),
+    },
+];
+
+/// Creates the keyspace if needed and applies every pending migration.
+pub async fn run(session: &scylla::Session, config: &DatabaseConfig) -> Result<()> {
+    session.query(
+        format!(
+            "CREATE KEYSPACE IF NOT EXISTS {} WITH replication = {{'class': 'SimpleStrategy', 'replication_factor' : 1}};",
+            &config.keyspace,
+        ),
+        &[],
+    ).await?;
+    session.use_keyspace(&config.keyspace, false).await?;
+
+    session.query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version int PRIMARY KEY,
+            name text,
+            applied_on timestamp
+        );
+        "#,
+        &[],
+    ).await?;
+
+    let applied = applied_versions(session).await?;
+
+    let mut count = 0;
+    for migration in MIGRATIONS.iter().filter(|m| !applied.contains(&m.version)) {
+        info!("Applying migration {:04}_{}", migration.version, migration.name);
+
+        for statement in statements(migration.cql) {
+            session.query(statement, &[]).await?;
+        }
+
+        session.query(
+            "INSERT INTO schema_migrations (version, name, applied_on) VALUES (?, ?, ?);",
+            (
+                migration.version,
+                migration.name,
+                Timestamp(chrono::Duration::milliseconds(chrono::Utc::now().timestamp_millis())),
+            ),
+        ).await?;
+
+        count += 1;
+    }
+
+    if count == 0 {
+        info!("Schema is up to date, no migrations applied");
+    } else {
+        info!("Applied {} migrations", count);
+    }
+
+    Ok(())
+}
+
+/// Errors if the keyspace has any migrations which haven't been applied.
+pub async fn ensure_up_to_date(session: &scylla::Session) -> Result<()> {
+    let applied = applied_versions(session)
+        .await
+        .map_err(|e| anyhow!("failed to read schema migrations, run `socketeer migrate` first: {}", e))?;
+
+    let pending: Vec<String> = MIGRATIONS
+        .iter()
+        .filter(|m| !applied.contains(&m.version))
+        .map(|m| format!("{:04}_{}", m.version, m.name))
+        .collect();
+
+    if !pending.is_empty() {
+        return Err(anyhow!(
+            "{} migrations are pending ({}), run `socketeer migrate` first",
+            pending.len(),
+            pending.join(", "),
+        ))
+    }
+
+    Ok(())
+}
+
+async fn applied_versions(session: &scylla::Session) -> Result<HashSet<i32>> {
+    let result = session.query("SELECT version FROM schema_migrations;", &[]).await?;
+
+    let mut versions = HashSet::new();
+    if let Some(rows) = result.rows {
+        for row in rows.into_typed::<(i32,)>() {
+            versions.insert(row?.0);
+        }
+    }
+
+    Ok(versions)
+}
+
+fn statements(cql: &str) -> impl Iterator<Item = &str> {
+    cql.split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+}
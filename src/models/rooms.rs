@@ -1,12 +1,15 @@
 use anyhow::{anyhow, Result};
 use scylla::{IntoTypedRows, FromRow};
 use uuid::Uuid;
-use poem_openapi::Object;
+use poem_openapi::{Enum, Object};
+use scylla::cql_to_rust::{FromCqlVal, FromCqlValError};
+use scylla::frame::response::result::CqlValue;
+use serde::{Deserialize, Serialize};
 
 use crate::db::Session;
 use crate::utils::JsSafeBigInt;
 
-#[derive(Object, FromRow)]
+#[derive(Object, FromRow, Serialize, Deserialize, Debug, Clone)]
 pub struct Room {
     pub id: Uuid,
     pub owner_id: JsSafeBigInt,
@@ -19,6 +22,58 @@ pub struct Room {
     pub playing_now: Option<Uuid>,
     pub title: String,
     pub topic: Option<String>,
+
+    /// How connections falling behind the room are handled, the
+    /// `emitter.lag_policy` config is used if not set.
+    pub lag_policy: Option<LagPolicy>,
+
+    /// The amount of events a connection can fall behind by before it lags,
+    /// the `emitter.broadcast_capacity` config is used if not set.
+    ///
+    /// Only applied when the room is started.
+    pub broadcast_capacity: Option<i32>,
+}
+
+/// What happens when a connection falls behind its room's events.
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[oai(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// The missed events are dropped and the connection is closed after
+    /// lagging more than a few times.
+    Disconnect,
+
+    /// The missed events are dropped and the connection carries on with
+    /// the oldest events still buffered.
+    DropOldest,
+
+    /// The missed events are delivered from the room's replay buffer. Falls
+    /// back to `Resync` if they are no longer buffered.
+    Coalesce,
+
+    /// The client is sent a `RESYNC` event to re-fetch the room's state and
+    /// the room events it still had queued are skipped.
+    Resync,
+}
+
+impl LagPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "disconnect" => Some(Self::Disconnect),
+            "drop_oldest" => Some(Self::DropOldest),
+            "coalesce" => Some(Self::Coalesce),
+            "resync" => Some(Self::Resync),
+            _ => None,
+        }
+    }
+}
+
+impl FromCqlVal<CqlValue> for LagPolicy {
+    fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
+        cql_val.as_text()
+            .and_then(|name| Self::from_name(name))
+            .ok_or(FromCqlValError::BadCqlType)
+    }
 }
 
 pub async fn get_room_by_id(sess: &Session, room_id: Uuid) -> Result<Option<Room>> {
@@ -35,7 +90,9 @@ pub async fn get_room_by_id(sess: &Session, room_id: Uuid) -> Result<Option<Room
             is_public,
             playing_now,
             title,
-            topic
+            topic,
+            lag_policy,
+            broadcast_capacity
         FROM rooms
         WHERE id = ?;
         "#,
@@ -51,4 +108,18 @@ pub async fn get_room_by_id(sess: &Session, room_id: Uuid) -> Result<Option<Room
     };
 
     Ok(Some(room))
-}
\ No newline at end of file
+}
+
+/// Checks if the user has been invited to the room.
+pub async fn is_invited(sess: &Session, room_id: Uuid, user_id: i64) -> Result<bool> {
+    let result = sess.query_prepared(
+        "SELECT user_id FROM room_invites WHERE room_id = ? AND user_id = ?;",
+        (room_id, user_id)
+    ).await?;
+
+    let invited = result.rows
+        .map(|rows| !rows.is_empty())
+        .unwrap_or(false);
+
+    Ok(invited)
+}
@@ -2,17 +2,20 @@ use std::collections::HashMap;
 use anyhow::anyhow;
 use scylla::IntoTypedRows;
 use poem_openapi::Object;
+use serde::Deserialize;
 
 use crate::db::Session;
 use crate::utils::JsSafeBigInt;
 
 
-#[derive(Object)]
+#[derive(Object, Deserialize, Clone)]
 pub struct User {
     pub id: JsSafeBigInt,
     #[oai(skip)]
+    #[serde(default)]
     pub access_servers: HashMap<i64, bool>,
     pub avatar: Option<String>,
+    #[serde(default)]
     pub updated_on: i64,
     pub username: String,
 }
@@ -38,16 +41,6 @@ pub async fn get_user_id_from_token(sess: &Session, token: &str) -> anyhow::Resu
     Ok(user_id)
 }
 
-/// Gets a full user object from the given access token.
-pub async fn get_user_from_token(sess: &Session, token: &str) -> anyhow::Result<Option<User>> {
-    let user_id = match get_user_id_from_token(sess, token).await? {
-        None => return Ok(None),
-        Some(user_id) => user_id,
-    };
-
-    get_user_from_id(sess, user_id).await
-}
-
 pub async fn get_user_from_id(sess: &Session, user_id: i64) -> anyhow::Result<Option<User>> {
     let result = sess.query_prepared(
         "SELECT id, access_servers, avatar, updated_on, username FROM users WHERE id = ?;",
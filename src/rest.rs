@@ -1,12 +1,19 @@
+use std::sync::Arc;
+
 use poem::Result;
 use poem::web::Data;
-use poem_openapi::{OpenApi, Object};
+use poem_openapi::{ApiResponse, Enum, OpenApi, Object};
+use poem_openapi::param::{Path, Query};
 use poem_openapi::payload::Json;
 use serde_json::Value;
 use uuid::Uuid;
 
-use crate::utils::{JsonResponse, SuperUserBearer};
-use crate::ws::Event;
+use crate::emitter::{Emitted, EmitterManager, Member, RoomStats};
+use crate::error::ApiError;
+use crate::models::Room;
+use crate::storage::{CacheInvalidation, CachedStorage, StorageCacheStats};
+use crate::utils::{Detail, JsSafeBigInt, JsonResponse, SuperUserBearer};
+use crate::ws::{Event, Targets};
 
 
 #[derive(Object, Debug)]
@@ -17,6 +24,200 @@ pub struct EventPayload {
     type_: String,
 
     data: Value,
+
+    /// Marks the event as a snapshot of some state, only the latest event
+    /// with the same key is delivered to lagging and new connections.
+    coalesce_key: Option<String>,
+
+    /// Only deliver the event to these users.
+    user_ids: Option<Vec<JsSafeBigInt>>,
+
+    /// Never deliver the event to these users.
+    exclude_user_ids: Option<Vec<JsSafeBigInt>>,
+}
+
+impl EventPayload {
+    fn into_event(self) -> Event {
+        make_event(self.type_, self.data, self.coalesce_key, self.user_ids, self.exclude_user_ids)
+    }
+}
+
+
+/// Emits many events in one request.
+#[derive(Object, Debug)]
+pub struct BatchEmitPayload {
+    /// Events which are each emitted to their own room.
+    #[oai(default, validator(max_items = 1000))]
+    events: Vec<EventPayload>,
+
+    /// An event which is emitted to every room in `room_ids`.
+    broadcast: Option<BroadcastPayload>,
+}
+
+#[derive(Object, Debug)]
+pub struct BroadcastPayload {
+    #[oai(validator(max_items = 1000))]
+    room_ids: Vec<Uuid>,
+
+    #[oai(rename = "type")]
+    type_: String,
+
+    data: Value,
+
+    /// Marks the event as a snapshot of some state, only the latest event
+    /// with the same key is delivered to lagging and new connections.
+    coalesce_key: Option<String>,
+
+    /// Only deliver the event to these users.
+    user_ids: Option<Vec<JsSafeBigInt>>,
+
+    /// Never deliver the event to these users.
+    exclude_user_ids: Option<Vec<JsSafeBigInt>>,
+}
+
+#[derive(Enum, Debug)]
+#[oai(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EmitStatus {
+    /// The event was delivered on this instance.
+    Delivered,
+
+    /// The target isn't connected to this instance, the event was forwarded
+    /// to the rest of the cluster.
+    Forwarded,
+
+    /// The target doesn't exist.
+    NotFound,
+}
+
+/// An event sent to every connection of a user.
+#[derive(Object, Debug)]
+pub struct UserEventPayload {
+    #[oai(rename = "type")]
+    type_: String,
+
+    data: Value,
+}
+
+/// Access tokens which are no longer valid.
+#[derive(Object, Debug)]
+pub struct RevokeTokensPayload {
+    #[oai(validator(max_items = 1000))]
+    access_tokens: Vec<String>,
+
+    /// The reason sent to the clients in the `CLOSE` event.
+    reason: Option<String>,
+}
+
+/// The outcome of sending something to a user's connections.
+#[derive(Object, Debug)]
+pub struct UserDelivery {
+    status: EmitStatus,
+
+    /// The amount of connections on this instance it was delivered to.
+    connections: usize,
+}
+
+impl From<Emitted> for UserDelivery {
+    fn from(emitted: Emitted) -> Self {
+        match emitted {
+            Emitted::Delivered(connections) => Self {
+                status: EmitStatus::Delivered,
+                connections,
+            },
+            Emitted::Forwarded => Self {
+                status: EmitStatus::Forwarded,
+                connections: 0,
+            },
+        }
+    }
+}
+
+/// The outcome of a single event in a batch.
+#[derive(Object, Debug)]
+pub struct EmitResult {
+    room_id: Uuid,
+
+    #[oai(rename = "type")]
+    type_: String,
+
+    status: EmitStatus,
+
+    /// The amount of receivers the event was delivered to on this instance.
+    receivers: usize,
+}
+
+/// The cached entries to drop so the next lookup reads from storage.
+#[derive(Object, Debug)]
+pub struct InvalidateCachePayload {
+    /// Users to drop along with every access token resolving to them.
+    #[oai(default)]
+    user_ids: Vec<JsSafeBigInt>,
+
+    #[oai(default)]
+    access_tokens: Vec<String>,
+
+    #[oai(default)]
+    room_ids: Vec<Uuid>,
+
+    /// Drop every cached entry.
+    #[oai(default)]
+    all: bool,
+}
+
+
+#[derive(ApiResponse)]
+pub enum RoomResponse {
+    /// The room's stats.
+    #[oai(status = 200)]
+    Ok(Json<RoomStats>),
+
+    /// The room is not active on this instance.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum RoomMembersResponse {
+    /// The users connected to the room.
+    #[oai(status = 200)]
+    Ok(Json<Vec<Member>>),
+
+    /// The room is not active on this instance.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum UserResponse {
+    /// The user's connections it was delivered to.
+    #[oai(status = 200)]
+    Ok(Json<UserDelivery>),
+
+    /// The user is not connected.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum UpdateRoomResponse {
+    /// The update was applied.
+    #[oai(status = 200)]
+    Ok,
+
+    /// The room's id doesn't match the path.
+    #[oai(status = 400)]
+    BadRequest(Json<Detail>),
+}
+
+#[derive(ApiResponse)]
+pub enum CloseRoomResponse {
+    /// The room was closed.
+    #[oai(status = 200)]
+    Ok,
+
+    /// The room is not active.
+    #[oai(status = 404)]
+    NotFound(Json<Detail>),
 }
 
 
@@ -33,17 +234,267 @@ impl RestApi {
     pub async fn emit_event(
         &self,
         event: Json<EventPayload>,
-        emitter: Data<&crate::emitter::EmitterManager>,
+        emitter: Data<&EmitterManager>,
         _token: SuperUserBearer,
     ) -> Result<JsonResponse> {
-        emitter.emit(
-            &event.0.room_id,
-            Event {
-                type_: event.0.type_,
-                data: event.0.data,
-            },
-        )?;
+        let room_id = event.0.room_id;
+        emitter.emit(&room_id, event.0.into_event())?;
 
         Ok(JsonResponse::Ok)
     }
-}
\ No newline at end of file
+
+    /// Batch Emit Events
+    ///
+    /// Emits several events in one request, either each event to its own room
+    /// or a single event to a list of rooms. Results are returned in the order
+    /// of `events` followed by `broadcast.room_ids`.
+    #[instrument(name = "batch-event-emitter", skip(self, _token, emitter))]
+    #[oai(path = "/emit/batch", method = "post")]
+    pub async fn emit_batch(
+        &self,
+        batch: Json<BatchEmitPayload>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> Json<Vec<EmitResult>> {
+        let BatchEmitPayload { events, broadcast } = batch.0;
+
+        let mut results = Vec::with_capacity(events.len());
+        for payload in events {
+            let room_id = payload.room_id;
+            results.push(emit_one(&emitter, room_id, payload.into_event()));
+        }
+
+        if let Some(broadcast) = broadcast {
+            let event = make_event(
+                broadcast.type_,
+                broadcast.data,
+                broadcast.coalesce_key,
+                broadcast.user_ids,
+                broadcast.exclude_user_ids,
+            );
+            for room_id in broadcast.room_ids {
+                results.push(emit_one(&emitter, room_id, event.clone()));
+            }
+        }
+
+        Json(results)
+    }
+
+    /// Emit User Event
+    ///
+    /// Emits an event to every connection of the user regardless of the room.
+    #[oai(path = "/users/:user_id/emit", method = "post")]
+    pub async fn emit_user_event(
+        &self,
+        user_id: Path<i64>,
+        event: Json<UserEventPayload>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> UserResponse {
+        let event = Event::new(event.0.type_, event.0.data);
+        user_response(user_id.0, emitter.emit_to_user(user_id.0, event))
+    }
+
+    /// Disconnect User
+    ///
+    /// Closes every connection of the user, each connection is sent a
+    /// `CLOSE` event with the given reason first.
+    #[oai(path = "/users/:user_id/disconnect", method = "post")]
+    pub async fn disconnect_user(
+        &self,
+        user_id: Path<i64>,
+        reason: Query<Option<String>>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> UserResponse {
+        let reason = reason.0.unwrap_or_else(|| "disconnected".to_string());
+        user_response(user_id.0, emitter.disconnect_user(user_id.0, reason))
+    }
+
+    /// Revoke Access Tokens
+    ///
+    /// Closes every connection opened with one of the access tokens and drops
+    /// them from the cache, each connection is sent a `CLOSE` event with the
+    /// given reason first. The tokens should be removed from storage
+    /// beforehand otherwise clients can simply reconnect.
+    #[oai(path = "/tokens/revoke", method = "post")]
+    pub async fn revoke_tokens(
+        &self,
+        payload: Json<RevokeTokensPayload>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> Json<UserDelivery> {
+        let RevokeTokensPayload { access_tokens, reason } = payload.0;
+        let reason = reason.unwrap_or_else(|| "access token revoked".to_string());
+
+        Json(UserDelivery::from(emitter.revoke_tokens(access_tokens, reason)))
+    }
+
+    /// List Rooms
+    ///
+    /// Lists the rooms active on this instance.
+    #[oai(path = "/rooms", method = "get")]
+    pub async fn list_rooms(
+        &self,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> Json<Vec<RoomStats>> {
+        Json(emitter.list_rooms())
+    }
+
+    /// Get Room
+    ///
+    /// Gets the stats of a room active on this instance.
+    #[oai(path = "/rooms/:room_id", method = "get")]
+    pub async fn get_room(
+        &self,
+        room_id: Path<Uuid>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> RoomResponse {
+        match emitter.room_stats(&room_id.0) {
+            Some(stats) => RoomResponse::Ok(Json(stats)),
+            None => RoomResponse::NotFound(Json(ApiError::RoomNotFound(room_id.0).detail())),
+        }
+    }
+
+    /// Get Room Members
+    ///
+    /// Lists the users connected to a room on this instance.
+    #[oai(path = "/rooms/:room_id/members", method = "get")]
+    pub async fn get_room_members(
+        &self,
+        room_id: Path<Uuid>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> RoomMembersResponse {
+        match emitter.room_members(&room_id.0) {
+            Some(members) => RoomMembersResponse::Ok(Json(members)),
+            None => RoomMembersResponse::NotFound(Json(ApiError::RoomNotFound(room_id.0).detail())),
+        }
+    }
+
+    /// Update Room
+    ///
+    /// Applies a change to the room's state on every instance. Deactivated
+    /// rooms are closed with clients sent a `CLOSE` event first, otherwise
+    /// clients which no longer have access to the room are disconnected.
+    #[oai(path = "/rooms/:room_id", method = "put")]
+    pub async fn update_room(
+        &self,
+        room_id: Path<Uuid>,
+        room: Json<Room>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> UpdateRoomResponse {
+        if room.0.id != room_id.0 {
+            return UpdateRoomResponse::BadRequest(Json(ApiError::BadRequest(format!("room id does not match {}", room_id.0)).detail()))
+        }
+
+        emitter.update_room(room.0);
+        UpdateRoomResponse::Ok
+    }
+
+    /// Close Room
+    ///
+    /// Force closes a room disconnecting all of its clients,
+    /// clients are sent a `CLOSE` event first if `warn_clients` is set.
+    #[oai(path = "/rooms/:room_id", method = "delete")]
+    pub async fn close_room(
+        &self,
+        room_id: Path<Uuid>,
+        warn_clients: Query<Option<bool>>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> CloseRoomResponse {
+        if emitter.force_close_room(&room_id.0, warn_clients.0.unwrap_or(false)) {
+            CloseRoomResponse::Ok
+        } else {
+            CloseRoomResponse::NotFound(Json(ApiError::RoomNotFound(room_id.0).detail()))
+        }
+    }
+
+    /// Get Cache Stats
+    ///
+    /// Gets the size and hit/miss counts of the token, user and room
+    /// caches on this instance.
+    #[oai(path = "/cache", method = "get")]
+    pub async fn cache_stats(
+        &self,
+        cache: Data<&Arc<CachedStorage>>,
+        _token: SuperUserBearer,
+    ) -> Json<StorageCacheStats> {
+        Json(cache.stats())
+    }
+
+    /// Invalidate Cache
+    ///
+    /// Drops cached users, access tokens and rooms on every instance so
+    /// changes made to them take effect immediately.
+    #[instrument(name = "cache-invalidator", skip(self, _token, emitter))]
+    #[oai(path = "/cache/invalidate", method = "post")]
+    pub async fn invalidate_cache(
+        &self,
+        payload: Json<InvalidateCachePayload>,
+        emitter: Data<&EmitterManager>,
+        _token: SuperUserBearer,
+    ) -> JsonResponse {
+        let payload = payload.0;
+        emitter.invalidate_cache(CacheInvalidation {
+            user_ids: payload.user_ids.into_iter().map(|id| id.0).collect(),
+            access_tokens: payload.access_tokens,
+            room_ids: payload.room_ids,
+            all: payload.all,
+        });
+
+        JsonResponse::Ok
+    }
+}
+
+fn user_response(user_id: i64, emitted: Emitted) -> UserResponse {
+    match emitted {
+        Emitted::Delivered(0) => UserResponse::NotFound(Json(ApiError::UserNotConnected(user_id).detail())),
+        emitted => UserResponse::Ok(Json(UserDelivery::from(emitted))),
+    }
+}
+
+fn emit_one(emitter: &EmitterManager, room_id: Uuid, event: Event) -> EmitResult {
+    let type_ = event.type_.clone();
+
+    let (status, receivers) = match emitter.emit(&room_id, event) {
+        Ok(Emitted::Delivered(receivers)) => (EmitStatus::Delivered, receivers),
+        Ok(Emitted::Forwarded) => (EmitStatus::Forwarded, 0),
+        Err(_) => (EmitStatus::NotFound, 0),
+    };
+
+    EmitResult {
+        room_id,
+        type_,
+        status,
+        receivers,
+    }
+}
+
+fn make_event(
+    type_: String,
+    data: Value,
+    coalesce_key: Option<String>,
+    user_ids: Option<Vec<JsSafeBigInt>>,
+    exclude_user_ids: Option<Vec<JsSafeBigInt>>,
+) -> Event {
+    let mut event = Event::new(type_, data);
+    event.coalesce_key = coalesce_key;
+
+    if user_ids.is_some() || exclude_user_ids.is_some() {
+        event.targets = Some(Arc::new(Targets {
+            user_ids: user_ids.map(|ids| ids.into_iter().map(|id| id.0).collect()),
+            exclude_user_ids: exclude_user_ids
+                .unwrap_or_default()
+                .into_iter()
+                .map(|id| id.0)
+                .collect(),
+        }));
+    }
+
+    event
+}
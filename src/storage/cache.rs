@@ -0,0 +1,270 @@
+use std::hash::Hash;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use dashmap::DashMap;
+use poem_openapi::Object;
+use prometheus::IntCounter;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::CacheConfig;
+use crate::metrics::METRICS;
+use crate::models::{Room, User};
+use super::{DynStorage, Storage};
+
+
+/// Storage which caches the lookups of another storage for a limited time.
+///
+/// Only found entries are cached so newly created tokens, users and rooms
+/// are picked up immediately, changes to existing ones need to be
+/// invalidated or wait out the TTL.
+pub struct CachedStorage {
+    inner: DynStorage,
+    tokens: TtlMap<String, i64>,
+    users: TtlMap<i64, User>,
+    rooms: TtlMap<Uuid, Room>,
+}
+
+/// The hit and miss counts of a cache.
+#[derive(Object, Debug)]
+pub struct CacheStats {
+    /// The amount of entries currently held, including expired ones
+    /// which haven't been pruned yet.
+    entries: usize,
+    hits: u64,
+    misses: u64,
+}
+
+/// The stats of every cache.
+#[derive(Object, Debug)]
+pub struct StorageCacheStats {
+    tokens: CacheStats,
+    users: CacheStats,
+    rooms: CacheStats,
+}
+
+/// The cached entries to drop so the next lookup reads from storage.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CacheInvalidation {
+    /// Users to drop along with every access token resolving to them.
+    pub user_ids: Vec<i64>,
+    pub access_tokens: Vec<String>,
+    pub room_ids: Vec<Uuid>,
+
+    /// Drop every cached entry.
+    pub all: bool,
+}
+
+impl CachedStorage {
+    /// Wraps the storage and starts a task pruning expired entries
+    /// every TTL for as long as the cache is alive.
+    pub fn start(inner: DynStorage, config: &CacheConfig) -> Arc<Self> {
+        let ttl = Duration::from_secs(config.ttl);
+
+        let cache = Arc::new(Self {
+            inner,
+            tokens: TtlMap::new("tokens", ttl, config.max_entries),
+            users: TtlMap::new("users", ttl, config.max_entries),
+            rooms: TtlMap::new("rooms", ttl, config.max_entries),
+        });
+
+        if !ttl.is_zero() {
+            tokio::spawn(prune_periodically(Arc::downgrade(&cache), ttl));
+        }
+
+        cache
+    }
+
+    pub fn invalidate(&self, invalidation: &CacheInvalidation) {
+        if invalidation.all {
+            self.tokens.clear();
+            self.users.clear();
+            self.rooms.clear();
+            return;
+        }
+
+        for user_id in invalidation.user_ids.iter() {
+            self.users.remove(user_id);
+        }
+
+        if !invalidation.user_ids.is_empty() {
+            self.tokens.retain(|_, user_id| !invalidation.user_ids.contains(user_id));
+        }
+
+        for token in invalidation.access_tokens.iter() {
+            self.tokens.remove(token.as_str());
+        }
+
+        for room_id in invalidation.room_ids.iter() {
+            self.rooms.remove(room_id);
+        }
+    }
+
+    /// Removes any expired entries.
+    pub fn prune(&self) {
+        self.tokens.prune();
+        self.users.prune();
+        self.rooms.prune();
+    }
+
+    pub fn stats(&self) -> StorageCacheStats {
+        StorageCacheStats {
+            tokens: self.tokens.stats(),
+            users: self.users.stats(),
+            rooms: self.rooms.stats(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for CachedStorage {
+    async fn get_user_id_from_token(&self, token: &str) -> Result<Option<i64>> {
+        if let Some(user_id) = self.tokens.get(token) {
+            return Ok(Some(user_id))
+        }
+
+        let user_id = self.inner.get_user_id_from_token(token).await?;
+        if let Some(user_id) = user_id {
+            self.tokens.insert(token.to_string(), user_id);
+        }
+
+        Ok(user_id)
+    }
+
+    async fn get_user_from_id(&self, user_id: i64) -> Result<Option<User>> {
+        if let Some(user) = self.users.get(&user_id) {
+            return Ok(Some(user))
+        }
+
+        let user = self.inner.get_user_from_id(user_id).await?;
+        if let Some(ref user) = user {
+            self.users.insert(user_id, user.clone());
+        }
+
+        Ok(user)
+    }
+
+    async fn get_room_by_id(&self, room_id: Uuid) -> Result<Option<Room>> {
+        if let Some(room) = self.rooms.get(&room_id) {
+            return Ok(Some(room))
+        }
+
+        let room = self.inner.get_room_by_id(room_id).await?;
+        if let Some(ref room) = room {
+            self.rooms.insert(room_id, room.clone());
+        }
+
+        Ok(room)
+    }
+
+    /// Invites aren't cached so they take effect immediately.
+    async fn is_invited(&self, room_id: Uuid, user_id: i64) -> Result<bool> {
+        self.inner.is_invited(room_id, user_id).await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.inner.ping().await
+    }
+}
+
+async fn prune_periodically(cache: Weak<CachedStorage>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+
+        match cache.upgrade() {
+            Some(cache) => cache.prune(),
+            None => break,
+        }
+    }
+}
+
+
+/// A map whose entries expire after a fixed TTL.
+struct TtlMap<K: Eq + Hash, V> {
+    entries: DashMap<K, (V, Instant)>,
+    ttl: Duration,
+    max_entries: usize,
+
+    /// Shared with the `/metrics` counters of the cache.
+    hits: IntCounter,
+    misses: IntCounter,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlMap<K, V> {
+    fn new(name: &'static str, ttl: Duration, max_entries: usize) -> Self {
+        let (hits, misses) = METRICS.cache(name);
+
+        Self {
+            entries: DashMap::new(),
+            ttl,
+            max_entries,
+            hits,
+            misses,
+        }
+    }
+
+    fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let value = self.entries
+            .get(key)
+            .filter(|entry| entry.1.elapsed() < self.ttl)
+            .map(|entry| entry.0.clone());
+
+        if value.is_some() {
+            self.hits.inc();
+        } else {
+            self.misses.inc();
+        }
+
+        value
+    }
+
+    fn insert(&self, key: K, value: V) {
+        if self.ttl.is_zero() {
+            return;
+        }
+
+        // Once full new entries are only cached after expired ones are pruned.
+        if self.entries.len() >= self.max_entries && !self.entries.contains_key(&key) {
+            return;
+        }
+
+        self.entries.insert(key, (value, Instant::now()));
+    }
+
+    fn remove<Q>(&self, key: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.entries.remove(key);
+    }
+
+    fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        self.entries.retain(|key, entry| f(key, &entry.0));
+    }
+
+    fn clear(&self) {
+        self.entries.clear();
+    }
+
+    fn prune(&self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| entry.1.elapsed() < ttl);
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.entries.len(),
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::models::{Room, User};
+use crate::utils::JsSafeBigInt;
+use super::Storage;
+
+
+/// The JSON fixture an in-memory storage is seeded from.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Fixture {
+    users: Vec<User>,
+
+    /// Access tokens mapped to the id of the user they belong to.
+    access_tokens: HashMap<String, JsSafeBigInt>,
+    rooms: Vec<Room>,
+
+    /// Rooms mapped to the ids of the users invited to them.
+    invites: HashMap<Uuid, Vec<JsSafeBigInt>>,
+}
+
+
+/// Storage held entirely in memory, used for running locally and in CI
+/// without a database.
+#[derive(Default)]
+pub struct MemoryStorage {
+    users: HashMap<i64, User>,
+    access_tokens: HashMap<String, i64>,
+    rooms: HashMap<Uuid, Room>,
+    invites: HashSet<(Uuid, i64)>,
+}
+
+impl MemoryStorage {
+    /// Loads the storage from a JSON fixture file.
+    pub fn from_fixture_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let raw = std::fs::read(path)
+            .with_context(|| format!("failed to read storage fixture {}", path.display()))?;
+
+        let fixture: Fixture = serde_json::from_slice(&raw)
+            .with_context(|| format!("failed to parse storage fixture {}", path.display()))?;
+
+        Ok(Self::from(fixture))
+    }
+}
+
+impl From<Fixture> for MemoryStorage {
+    fn from(fixture: Fixture) -> Self {
+        Self {
+            users: fixture.users
+                .into_iter()
+                .map(|user| (*user.id, user))
+                .collect(),
+            access_tokens: fixture.access_tokens
+                .into_iter()
+                .map(|(token, user_id)| (token, *user_id))
+                .collect(),
+            rooms: fixture.rooms
+                .into_iter()
+                .map(|room| (room.id, room))
+                .collect(),
+            invites: fixture.invites
+                .into_iter()
+                .flat_map(|(room_id, user_ids)| {
+                    user_ids.into_iter().map(move |user_id| (room_id, *user_id))
+                })
+                .collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn get_user_id_from_token(&self, token: &str) -> Result<Option<i64>> {
+        Ok(self.access_tokens.get(token).copied())
+    }
+
+    async fn get_user_from_id(&self, user_id: i64) -> Result<Option<User>> {
+        Ok(self.users.get(&user_id).cloned())
+    }
+
+    async fn get_room_by_id(&self, room_id: Uuid) -> Result<Option<Room>> {
+        Ok(self.rooms.get(&room_id).cloned())
+    }
+
+    async fn is_invited(&self, room_id: Uuid, user_id: i64) -> Result<bool> {
+        Ok(self.invites.contains(&(room_id, user_id)))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+mod cache;
+mod memory;
+mod scylla;
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::models::{Room, User};
+
+pub use self::cache::{CacheInvalidation, CachedStorage, StorageCacheStats};
+pub use self::memory::MemoryStorage;
+pub use self::scylla::ScyllaStorage;
+
+/// The storage shared between handlers.
+pub type DynStorage = Arc<dyn Storage>;
+
+
+/// Where users, access tokens and rooms are looked up from.
+#[async_trait]
+pub trait Storage: Send + Sync + 'static {
+    /// Gets a user_id from the given access token if it's valid otherwise return None.
+    async fn get_user_id_from_token(&self, token: &str) -> Result<Option<i64>>;
+
+    async fn get_user_from_id(&self, user_id: i64) -> Result<Option<User>>;
+
+    async fn get_room_by_id(&self, room_id: Uuid) -> Result<Option<Room>>;
+
+    /// Checks if the user has been invited to the room.
+    async fn is_invited(&self, room_id: Uuid, user_id: i64) -> Result<bool>;
+
+    /// Checks the storage can currently serve requests.
+    async fn ping(&self) -> Result<()>;
+
+    /// Gets a full user object from the given access token.
+    async fn get_user_from_token(&self, token: &str) -> Result<Option<User>> {
+        let user_id = match self.get_user_id_from_token(token).await? {
+            None => return Ok(None),
+            Some(user_id) => user_id,
+        };
+
+        self.get_user_from_id(user_id).await
+    }
+}
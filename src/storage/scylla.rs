@@ -0,0 +1,41 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::db::Session;
+use crate::models::{self, Room, User};
+use super::Storage;
+
+
+/// Storage backed by ScyllaDB.
+pub struct ScyllaStorage(Session);
+
+impl From<Session> for ScyllaStorage {
+    fn from(session: Session) -> Self {
+        Self(session)
+    }
+}
+
+#[async_trait]
+impl Storage for ScyllaStorage {
+    async fn get_user_id_from_token(&self, token: &str) -> Result<Option<i64>> {
+        models::get_user_id_from_token(&self.0, token).await
+    }
+
+    async fn get_user_from_id(&self, user_id: i64) -> Result<Option<User>> {
+        models::get_user_from_id(&self.0, user_id).await
+    }
+
+    async fn get_room_by_id(&self, room_id: Uuid) -> Result<Option<Room>> {
+        models::get_room_by_id(&self.0, room_id).await
+    }
+
+    async fn is_invited(&self, room_id: Uuid, user_id: i64) -> Result<bool> {
+        models::is_invited(&self.0, room_id, user_id).await
+    }
+
+    async fn ping(&self) -> Result<()> {
+        self.0.query("SELECT now() FROM system.local;", &[]).await?;
+        Ok(())
+    }
+}
@@ -9,10 +9,11 @@ use poem_openapi::auth::Bearer;
 use poem_openapi::registry::MetaSchemaRef;
 use scylla::cql_to_rust::{FromCqlVal, FromCqlValError};
 use scylla::frame::response::result::CqlValue;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value};
 
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct JsSafeBigInt(pub i64);
 
 impl Display for JsSafeBigInt {
@@ -59,12 +60,41 @@ impl ToJSON for JsSafeBigInt {
 
 impl ParseFromJSON for JsSafeBigInt {
     fn parse_from_json(value: Value) -> ParseResult<Self> {
-        value.as_i64()
-            .map(|v| Self(v))
+        // Ids are sent to clients as strings so accept them back in either form.
+        let id = match &value {
+            Value::String(s) => s.parse::<i64>().ok(),
+            other => other.as_i64(),
+        };
+
+        id.map(Self)
             .ok_or_else(|| ParseError::custom("cannot convert value into integer"))
     }
 }
 
+impl<'de> Deserialize<'de> for JsSafeBigInt {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Int(i64),
+            Str(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Int(id) => Ok(Self(id)),
+            Repr::Str(s) => s.parse::<i64>()
+                .map(Self)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl Serialize for JsSafeBigInt {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&self.0)
+    }
+}
+
 impl FromStr for JsSafeBigInt {
     type Err = poem_openapi::types::ParseError<Self>;
 
@@ -77,8 +107,8 @@ impl FromStr for JsSafeBigInt {
 impl FromCqlVal<CqlValue> for JsSafeBigInt {
     fn from_cql(cql_val: CqlValue) -> Result<Self, FromCqlValError> {
         cql_val.as_bigint()
-            .map(|v| Self(v))
-            .ok_or_else(|| FromCqlValError::BadCqlType)
+            .map(Self)
+            .ok_or(FromCqlValError::BadCqlType)
     }
 }
 
@@ -89,6 +119,7 @@ lazy_static!{
     };
 }
 
+#[allow(unused)]
 #[derive(SecurityScheme)]
 #[oai(type = "bearer")]
 pub struct TokenBearer(pub Bearer);
@@ -112,12 +143,25 @@ async fn token_checker(_: &Request, bearer: Bearer) -> Option<()> {
 pub struct Detail {
     /// More information for the given error.
     detail: String,
+
+    /// A stable identifier for the error clients can match on.
+    reason: Option<String>,
+}
+
+impl Detail {
+    pub fn with_reason(msg: String, reason: &str) -> Self {
+        Self {
+            detail: msg,
+            reason: Some(reason.to_string()),
+        }
+    }
 }
 
 impl From<String> for Detail {
     fn from(msg: String) -> Self {
         Self {
-            detail: msg
+            detail: msg,
+            reason: None,
         }
     }
 }
@@ -1,116 +1,1011 @@
-use futures_util::SinkExt;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use futures_util::stream::SplitSink;
 use poem::{handler, web::{
-    websocket::{Message, WebSocket},
+    websocket::{CloseCode, Message, WebSocket, WebSocketStream},
     Data, Query,
-}, IntoResponse, Response, Result};
-use reqwest::StatusCode;
+}, IntoResponse, Request, Response, Result};
+use poem::http::header::{self, HeaderValue};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use tokio::sync::broadcast::error::RecvError;
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::time::{Instant, Interval};
 use uuid::Uuid;
 
-use crate::db::Session;
+use crate::authz::{self, Denial};
+use crate::error::ApiError;
+use crate::emitter::{ConnectionCommand, EmitterManager, Lagged, Subscription};
+use crate::encoding::{Compression, EncodedFrames, Encoding};
+use crate::metrics::METRICS;
+use crate::models::{LagPolicy, Room, User};
+use crate::storage::DynStorage;
+
+/// Event types produced by socketeer itself which clients are not
+/// allowed to publish.
+const RESERVED_EVENTS: &[&str] = &[
+    "PING",
+    "CLOSE",
+    "ERROR",
+    "READY",
+    "RESUMED",
+    "MEMBER_JOIN",
+    "MEMBER_LEAVE",
+    "RECONNECT",
+    "RESYNC",
+];
+
+/// The amount of consecutive `PING`s a client can leave un-acknowledged
+/// before the connection is considered dead.
+const MAX_MISSED_ACKS: usize = 3;
+
+/// The amount of times a connection can lag behind before being closed
+/// when the room's lag policy is `disconnect`.
+const MAX_LAGS: usize = 3;
+
+/// Round trips slower than this are logged as a warning.
+const SLOW_RTT: Duration = Duration::from_secs(5);
+
+/// Close code sent when the client's access token is no longer valid.
+pub const CLOSE_UNAUTHORIZED: u16 = 4001;
+
+/// Close code sent when the client loses access to the room.
+const CLOSE_FORBIDDEN: u16 = 4003;
+
+/// Close code sent when the room is closed.
+const CLOSE_ROOM_CLOSED: u16 = 4004;
 
+/// Close code sent when the client falls too far behind the room's events.
+const CLOSE_LAGGING: u16 = 4008;
 
+/// Close code sent when the client stops acknowledging heartbeats.
+const CLOSE_HEARTBEAT_TIMEOUT: u16 = 4009;
 
-#[derive(Serialize, Debug, Clone)]
+type Sink = SplitSink<WebSocketStream, Message>;
+
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Event {
     #[serde(rename = "type")]
     pub type_: String,
 
     pub data: Value,
+
+    /// The room's sequence number for this event, only set for
+    /// events which can be replayed when resuming a session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+
+    /// Events with the same key are snapshots of the same state, only the
+    /// latest of which matters.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coalesce_key: Option<String>,
+
+    /// The users the event is delivered to, `None` for everyone in the room.
+    #[serde(skip)]
+    pub targets: Option<Arc<Targets>>,
+
+    /// The frames already encoded, shared by every clone of the event.
+    #[serde(skip)]
+    frames: EncodedFrames,
+}
+
+/// Limits which users in a room an event is delivered to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Targets {
+    /// Only these users receive the event, `None` for everyone.
+    pub user_ids: Option<HashSet<i64>>,
+
+    /// These users never receive the event.
+    pub exclude_user_ids: HashSet<i64>,
+}
+
+impl Targets {
+    pub fn includes(&self, user_id: i64) -> bool {
+        if self.exclude_user_ids.contains(&user_id) {
+            return false;
+        }
+
+        self.user_ids
+            .as_ref()
+            .map(|user_ids| user_ids.contains(&user_id))
+            .unwrap_or(true)
+    }
+}
+
+impl Event {
+    pub fn new(type_: impl Into<String>, data: Value) -> Self {
+        Self {
+            type_: type_.into(),
+            data,
+            seq: None,
+            coalesce_key: None,
+            targets: None,
+            frames: Default::default(),
+        }
+    }
+
+    /// Sets the room's sequence number, dropping any frames encoded without it.
+    pub fn set_seq(&mut self, seq: u64) {
+        self.seq = Some(seq);
+        self.frames = Default::default();
+    }
+
+    /// Encodes the event in the default format ahead of it being
+    /// delivered, any other formats are encoded by the first connection
+    /// using them.
+    pub fn pre_encode(&self) {
+        self.frames.get_or_encode(Encoding::default(), Compression::default(), self).unwrap();
+    }
+
+    /// The frame delivering the event in the given format.
+    fn frame(&self, encoding: Encoding, compression: Compression) -> Message {
+        self.frames.get_or_encode(encoding, compression, self).unwrap().to_message()
+    }
+
+    pub fn ping(seq: u64) -> Self {
+        Self::new("PING", json!({ "seq": seq }))
+    }
+
+    fn error(detail: impl Into<String>) -> Self {
+        Self::new("ERROR", json!({ "detail": detail.into() }))
+    }
+
+    fn is_reserved(&self) -> bool {
+        RESERVED_EVENTS.contains(&self.type_.as_str())
+    }
+}
+
+/// A frame sent by the client to the gateway.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", content = "data", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClientOp {
+    /// Acknowledges the `PING` with the given sequence number.
+    HeartbeatAck {
+        seq: u64,
+    },
+
+    /// Limits the events delivered to this connection to the given types,
+    /// built in events are always delivered.
+    Subscribe {
+        types: Vec<String>,
+    },
+
+    /// Publishes an event to everyone connected to the room.
+    Publish {
+        #[serde(rename = "type")]
+        type_: String,
+
+        #[serde(default)]
+        data: Value,
+    },
 }
 
 #[derive(Deserialize)]
 pub struct QueryParams {
     room_id: Uuid,
     token: String,
+
+    /// The session to resume, requires `last_seq`.
+    session_id: Option<Uuid>,
+
+    /// The sequence number of the last event the client received.
+    last_seq: Option<u64>,
+
+    /// How events are encoded, takes precedence over `Sec-WebSocket-Protocol`.
+    encoding: Option<String>,
+
+    /// How frames sent to the client are compressed, `zlib` or `zstd`.
+    compression: Option<String>,
 }
 
 #[handler]
 pub async fn gateway(
-    Query(QueryParams { room_id, token }): Query<QueryParams>,
+    Query(QueryParams { room_id, token, session_id, last_seq, encoding, compression }): Query<QueryParams>,
+    req: &Request,
     ws: WebSocket,
-    session: Data<&Session>,
-    emitter: Data<&crate::emitter::EmitterManager>,
+    storage: Data<&DynStorage>,
+    emitter: Data<&EmitterManager>,
 ) -> Result<Response> {
-    let user = match crate::models::get_user_from_token(&session, &token).await? {
-        None => return Ok((StatusCode::UNAUTHORIZED, "unauthorized user").into_response()),
-        Some(user) => user,
-    };
+    if emitter.is_draining() {
+        return Err(ApiError::Draining.into());
+    }
+
+    let (encoding, protocol) = negotiate_encoding(
+        encoding.as_deref(),
+        req.headers().get(header::SEC_WEBSOCKET_PROTOCOL),
+    )?;
 
-    let room = match crate::models::get_room_by_id(&session, room_id).await? {
-        None => return Ok((StatusCode::BAD_REQUEST, "no room exists").into_response()),
-        Some(room) => room,
+    let compression = match compression.as_deref() {
+        Some(name) => Compression::from_name(name).ok_or_else(|| {
+            ApiError::BadRequest(format!("unknown compression {}, expected none, zlib or zstd", name))
+        })?,
+        None => Compression::None,
     };
 
-    if !room.active {
-        return Ok((StatusCode::BAD_REQUEST, "room closed").into_response())
+    let user = storage.get_user_from_token(&token)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let room = storage.get_room_by_id(room_id)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or(ApiError::RoomNotFound(room_id))?;
+
+    authz::authorize(storage.as_ref(), &user, &room)
+        .await
+        .map_err(ApiError::from)?
+        .map_err(ApiError::Denied)?;
+
+    emitter.register_room(&room);
+    let subscription = emitter.subscribe(&room_id, &user, &token, session_id.zip(last_seq))?;
+    let emitter = emitter.clone();
+    let storage = storage.clone();
+
+    let resp = ws.protocols(protocol).on_upgrade(move |socket| async move {
+        let (sink, stream) = socket.split();
+
+        let conn = Connection {
+            user,
+            room,
+            token,
+            storage,
+            emitter,
+            session_id: subscription.session_id,
+            sink,
+            encoding,
+            compression,
+            subscriptions: None,
+            lag_count: 0,
+            last_seq: 0,
+            reconnecting: false,
+            pending_ping: None,
+            missed_acks: 0,
+            rtt: None,
+        };
+
+        conn.run(subscription, stream).await;
+    }).into_response();
+
+    Ok(resp)
+}
+
+/// A single upgraded gateway connection.
+struct Connection {
+    user: User,
+    room: Room,
+
+    /// The access token the connection was opened with.
+    token: String,
+    storage: DynStorage,
+    emitter: EmitterManager,
+    session_id: Uuid,
+    sink: Sink,
+    encoding: Encoding,
+    compression: Compression,
+
+    /// The event types the client has asked for, `None` means everything.
+    subscriptions: Option<HashSet<String>>,
+    lag_count: usize,
+
+    /// The sequence number of the last room event received, events up to
+    /// it are skipped if they're received again.
+    last_seq: u64,
+
+    /// Set once the `RECONNECT` event sent when draining is delivered.
+    reconnecting: bool,
+
+    /// The sequence number of the last `PING` sent and when it was sent.
+    pending_ping: Option<(u64, Instant)>,
+    missed_acks: usize,
+    rtt: Option<Duration>,
+}
+
+impl Connection {
+    async fn run(
+        mut self,
+        subscription: Subscription,
+        mut stream: futures_util::stream::SplitStream<WebSocketStream>,
+    ) {
+        let mut receiver = subscription.receiver;
+        let mut commands = subscription.commands;
+        let mut updates = subscription.updates;
+        let guard = subscription.guard;
+
+        let started = self.start_session(
+            subscription.seq,
+            subscription.replay,
+            subscription.latest,
+        ).await;
+
+        if started.is_ok() {
+            self.process(&mut receiver, &mut commands, &mut updates, &mut stream).await;
+        }
+
+        drop(receiver);
+        drop(guard);
+        let _ = self.sink.close().await;
     }
 
-    let has_guild_access = if let Some(guild_id) = room.guild_id.as_ref() {
-      user.access_servers.contains_key(&*guild_id)
-    } else {
-        false
-    };
+    /// Sends the `READY` event followed by the latest event of each
+    /// coalesce key for new sessions, or replays the missed events
+    /// followed by a `RESUMED` event for resumed sessions.
+    async fn start_session(
+        &mut self,
+        seq: u64,
+        replay: Option<Vec<Arc<Event>>>,
+        latest: Vec<Arc<Event>>,
+    ) -> io::Result<()> {
+        let replay = match replay {
+            None => {
+                let ready = Event::new("READY", json!({
+                    "session_id": self.session_id,
+                    "seq": seq,
+                }));
+                self.send(&ready).await?;
+
+                for event in latest {
+                    self.feed(&event).await?;
+                }
+                self.last_seq = seq;
+
+                return self.sink.flush().await;
+            },
+            Some(replay) => replay,
+        };
+
+        debug!("User {} resumed session {} replaying {} events", &self.user.id, &self.session_id, replay.len());
 
-    if (!room.is_public)                // The room is not public
-        & (!room.invite_only)           // The room is not invite only
-        & (room.owner_id != user.id)    // They are not the owner of the room
-        & (!has_guild_access)           // They don't have access via guilds.
-    {
-        return Ok((StatusCode::FORBIDDEN, "no access").into_response())
+        let replayed = replay.len();
+        for event in replay {
+            self.feed(&event).await?;
+        }
+        self.last_seq = seq;
+
+        let resumed = Event::new("RESUMED", json!({
+            "session_id": self.session_id,
+            "replayed": replayed,
+        }));
+        self.send(&resumed).await
     }
 
-    emitter.register_room(room_id.clone());
-    let mut receiver = emitter.get_subscriber(&room_id);
+    async fn process(
+        &mut self,
+        receiver: &mut broadcast::Receiver<Arc<Event>>,
+        commands: &mut mpsc::Receiver<ConnectionCommand>,
+        updates: &mut watch::Receiver<Option<Room>>,
+        stream: &mut futures_util::stream::SplitStream<WebSocketStream>,
+    ) {
+        let mut revalidate = match self.emitter.config().token_revalidate_interval {
+            0 => None,
+            secs => {
+                let period = Duration::from_secs(secs);
+                Some(tokio::time::interval_at(Instant::now() + period, period))
+            },
+        };
 
-    let resp = ws.on_upgrade(move |mut socket| async move {
-        let mut lag_count = 0;
+        let mut updates_open = true;
         loop {
-            while let Ok(event) = receiver.try_recv() {
-                let msg = Message::Binary(serde_json::to_vec(&event).unwrap());
-                if socket.feed(msg).await.is_err() {
-                    break;
-                };
-            }
+            tokio::select! {
+                _ = tick(&mut revalidate) => {
+                    match self.storage.get_user_id_from_token(&self.token).await {
+                        Ok(Some(user_id)) if user_id == *self.user.id => {},
+                        Ok(_) => {
+                            info!("Disconnecting user {} connection: access token revoked", &self.user.id);
+                            self.close("access token revoked", CloseCode::from(CLOSE_UNAUTHORIZED)).await;
+                            break;
+                        },
+                        Err(e) => warn!("Failed to re-validate access token of user {}: {}", &self.user.id, e),
+                    }
+                },
+                changed = updates.changed(), if updates_open => {
+                    // The room was closed, the receiver is drained before
+                    // it reports being closed so no events are missed.
+                    if changed.is_err() {
+                        updates_open = false;
+                        continue;
+                    }
+
+                    let room = match updates.borrow().clone() {
+                        Some(room) => room,
+                        None => continue,
+                    };
 
-            match receiver.recv().await {
-                Ok(event) => {
-                    let msg = Message::Binary(serde_json::to_vec(&event).unwrap());
-                    if socket.feed(msg).await.is_err() {
+                    if !self.on_room_update(room).await {
                         break;
+                    }
+                },
+                event = receiver.recv() => {
+                    let mut received = event;
+                    let open = loop {
+                        match received {
+                            Ok(event) => {
+                                if self.feed(&event).await.is_err() {
+                                    break false;
+                                }
+                            },
+                            Err(RecvError::Lagged(n)) => {
+                                warn!("User {} connection is lagging behind, {} events skipped.", &self.user.id, n);
+                                METRICS.lag_events.inc();
+
+                                match self.on_lagged(receiver, n).await {
+                                    Ok(true) => {},
+                                    Ok(false) | Err(_) => break false,
+                                }
+                            },
+                            Err(RecvError::Closed) => {
+                                self.on_room_closed().await;
+                                break false;
+                            },
+                        }
+
+                        // Closed is only reported once so it can't be left
+                        // for the next `recv` to pick up.
+                        received = match receiver.try_recv() {
+                            Ok(event) => Ok(event),
+                            Err(TryRecvError::Lagged(n)) => Err(RecvError::Lagged(n)),
+                            Err(TryRecvError::Closed) => Err(RecvError::Closed),
+                            Err(TryRecvError::Empty) => break true,
+                        };
                     };
+
+                    if !open {
+                        break;
+                    }
                 },
-                Err(RecvError::Lagged(n)) => {
-                    warn!("User {} connection is lagging behind, {} events skipped.", &user.id, n);
-
-                    lag_count += 1;
-
-                    if lag_count > 3 {
-                        warn!("Aborting user connection {} due to too many lagged events.", &user.id);
-                        let _ = socket.send(
-                            Message::Binary(serde_json::to_vec(&Event {
-                                type_: "CLOSE".to_string(),
-                                data: Value::Null,
-                            }).unwrap())
-                        ).await;
+                command = commands.recv() => {
+                    match command {
+                        Some(ConnectionCommand::Send(event)) => {
+                            if self.feed(&event).await.is_err() {
+                                break;
+                            }
+                        },
+                        Some(ConnectionCommand::Disconnect { reason, code }) => {
+                            info!("Disconnecting user {} connection: {}", &self.user.id, &reason);
+                            let code = code.map(CloseCode::from).unwrap_or(CloseCode::Normal);
+                            self.close(&reason, code).await;
+                            break;
+                        },
+                        None => break,
+                    }
+                },
+                msg = stream.next() => {
+                    // Text frames are always JSON, binary frames use the
+                    // connection's encoding.
+                    let (payload, encoding) = match msg {
+                        Some(Ok(Message::Text(text))) => (text.into_bytes(), Encoding::JsonText),
+                        Some(Ok(Message::Binary(data))) => (data, self.encoding),
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => continue,
+                    };
+
+                    if self.handle_client_frame(&payload, encoding).await.is_err() {
                         break;
                     }
+                },
+            }
 
-                    continue;
-                }
-                Err(_) => break,
-            };
+            if self.reconnecting {
+                info!("Closing user {} connection, the server is shutting down", &self.user.id);
+                let _ = self.sink.send(Message::close_with(CloseCode::Away, "server shutting down")).await;
+                break;
+            }
+
+            if self.missed_acks >= MAX_MISSED_ACKS {
+                warn!(
+                    "Aborting user connection {} after {} missed heartbeats, last rtt {:?}.",
+                    &self.user.id,
+                    self.missed_acks,
+                    self.rtt,
+                );
+                self.close("heartbeat timeout", CloseCode::from(CLOSE_HEARTBEAT_TIMEOUT)).await;
+                break;
+            }
 
-            if let Err(e) = socket.flush().await {
+            if let Err(e) = self.sink.flush().await {
                 error!("Aborting connection due to flush error {}", e);
                 break;
             };
         }
+    }
 
-        let _ = socket.close().await;
-    }).into_response();
+    /// Re-checks the user's access after the room's state changed,
+    /// returns `false` if the connection was closed.
+    async fn on_room_update(&mut self, room: Room) -> bool {
+        self.room = room;
 
-    Ok(resp)
+        let denial = match authz::authorize(self.storage.as_ref(), &self.user, &self.room).await {
+            Ok(Ok(())) => return true,
+            Ok(Err(denial)) => denial,
+            Err(e) => {
+                warn!("Failed to re-check access of user {} to room {}: {}", &self.user.id, &self.room.id, e);
+                return true
+            },
+        };
+
+        let code = match denial {
+            Denial::RoomInactive => CLOSE_ROOM_CLOSED,
+            _ => CLOSE_FORBIDDEN,
+        };
+
+        info!("User {} lost access to room {}: {}", &self.user.id, &self.room.id, denial);
+        self.close(&denial.to_string(), CloseCode::from(code)).await;
+        false
+    }
+
+    /// Sends the close frame once the room has been closed, clients are
+    /// warned with a `CLOSE` event by the room itself if requested.
+    async fn on_room_closed(&mut self) {
+        let _ = self.sink.send(Message::close_with(
+            CloseCode::from(CLOSE_ROOM_CLOSED),
+            "room closed",
+        )).await;
+    }
+
+    /// Applies the room's lag policy after the connection fell behind
+    /// and missed `skipped` events, returns `false` if the connection
+    /// was closed.
+    async fn on_lagged(
+        &mut self,
+        receiver: &mut broadcast::Receiver<Arc<Event>>,
+        skipped: u64,
+    ) -> io::Result<bool> {
+        let policy = self.room.lag_policy.unwrap_or(self.emitter.config().lag_policy);
+
+        // The room has been closed, which the receiver reports next.
+        let lagged = match self.emitter.recover_lagged(&self.room.id, self.last_seq, receiver) {
+            Some(lagged) => lagged,
+            None => return Ok(true),
+        };
+
+        let seq = lagged.seq;
+        let catch_up = catch_up(policy, lagged, skipped, |event| self.wants(event));
+        METRICS.dropped(catch_up.dropped, "lagged");
+
+        if policy == LagPolicy::Disconnect && catch_up.lost_unkeyed {
+            self.lag_count += 1;
+
+            if self.lag_count > MAX_LAGS {
+                warn!("Aborting user connection {} due to too many lagged events.", &self.user.id);
+                METRICS.lag_kicks.inc();
+                self.close("lagging behind", CloseCode::from(CLOSE_LAGGING)).await;
+                return Ok(false)
+            }
+        }
+
+        debug!(
+            "User {} catching up on room {} with {} events, resync: {}",
+            &self.user.id,
+            &self.room.id,
+            catch_up.events.len(),
+            catch_up.resync,
+        );
+
+        for event in catch_up.events {
+            self.feed(&event).await?;
+        }
+
+        if catch_up.resync {
+            self.feed(&Event::new("RESYNC", json!({
+                "seq": seq,
+                "skipped": skipped,
+            }))).await?;
+        }
+
+        // Every event up to `seq` has been drained from the receiver.
+        self.last_seq = self.last_seq.max(seq);
+        Ok(true)
+    }
+
+    /// Checks if the event is for this connection and the client has
+    /// subscribed to it.
+    fn wants(&self, event: &Event) -> bool {
+        if let Some(targets) = event.targets.as_ref() {
+            if !targets.includes(*self.user.id) {
+                return false
+            }
+        }
+
+        if let Some(subscriptions) = self.subscriptions.as_ref() {
+            if !event.is_reserved() && !subscriptions.contains(&event.type_) {
+                return false
+            }
+        }
+
+        true
+    }
+
+    /// Queues the event to be written to the socket if the client wants it.
+    ///
+    /// Room events are skipped if they've already been received.
+    async fn feed(&mut self, event: &Event) -> io::Result<()> {
+        if let Some(seq) = event.seq {
+            if seq <= self.last_seq {
+                return Ok(())
+            }
+
+            self.last_seq = seq;
+        }
+
+        if !self.wants(event) {
+            return Ok(())
+        }
+
+        self.deliver(event).await
+    }
+
+    /// Queues the event to be written to the socket.
+    async fn deliver(&mut self, event: &Event) -> io::Result<()> {
+        if event.type_ == "PING" {
+            self.on_ping(event);
+        }
+
+        if event.type_ == "RECONNECT" && self.emitter.is_draining() {
+            self.reconnecting = true;
+        }
+
+        self.sink.feed(event.frame(self.encoding, self.compression)).await?;
+
+        METRICS.events_delivered.inc();
+        Ok(())
+    }
+
+    /// Tracks an outgoing `PING`, counting the previous one as missed
+    /// if the client never acknowledged it.
+    fn on_ping(&mut self, event: &Event) {
+        if self.pending_ping.is_some() {
+            self.missed_acks += 1;
+            debug!("User {} missed heartbeat, {} missed in a row", &self.user.id, self.missed_acks);
+        }
+
+        if let Some(seq) = event.data.get("seq").and_then(Value::as_u64) {
+            self.pending_ping = Some((seq, Instant::now()));
+        }
+    }
+
+    fn on_heartbeat_ack(&mut self, seq: u64) {
+        let (pending_seq, sent_at) = match self.pending_ping {
+            Some(pending) => pending,
+            None => return,
+        };
+
+        if pending_seq != seq {
+            debug!("User {} acknowledged stale heartbeat {}, expected {}", &self.user.id, seq, pending_seq);
+            return;
+        }
+
+        let rtt = sent_at.elapsed();
+        METRICS.heartbeat_rtt.observe(rtt.as_secs_f64());
+
+        if rtt >= SLOW_RTT {
+            warn!("User {} has a slow heartbeat round trip of {:?}", &self.user.id, rtt);
+        } else {
+            trace!("User {} acknowledged heartbeat in {:?}", &self.user.id, rtt);
+        }
+
+        self.rtt = Some(rtt);
+        self.pending_ping = None;
+        self.missed_acks = 0;
+    }
+
+    async fn send(&mut self, event: &Event) -> io::Result<()> {
+        self.sink.send(event.frame(self.encoding, self.compression)).await
+    }
+
+    /// Sends a `CLOSE` event with the reason followed by a close frame.
+    async fn close(&mut self, reason: &str, code: CloseCode) {
+        let _ = self.send(&Event::new("CLOSE", json!({ "reason": reason }))).await;
+        let _ = self.sink.send(Message::close_with(code, reason)).await;
+    }
+
+    /// Parses and actions a frame sent by the client.
+    ///
+    /// Only socket errors are returned, invalid frames are reported
+    /// back to the client as an `ERROR` event.
+    async fn handle_client_frame(&mut self, payload: &[u8], encoding: Encoding) -> io::Result<()> {
+        let op = match encoding.decode::<ClientOp>(payload) {
+            Ok(op) => op,
+            Err(e) => {
+                debug!("User {} sent an invalid frame: {}", &self.user.id, e);
+                return self.feed(&Event::error(format!("invalid frame: {}", e))).await;
+            },
+        };
+
+        match op {
+            ClientOp::HeartbeatAck { seq } => {
+                self.on_heartbeat_ack(seq);
+            },
+            ClientOp::Subscribe { types } => {
+                self.subscriptions = Some(types.into_iter().collect());
+            },
+            ClientOp::Publish { type_, data } => {
+                let event = Event::new(type_, json!({
+                    "author_id": self.user.id.to_string(),
+                    "data": data,
+                }));
+
+                if let Err(detail) = self.check_publish(&event) {
+                    return self.feed(&Event::error(detail)).await;
+                }
+
+                if let Err(e) = self.emitter.emit(&self.room.id, event) {
+                    return self.feed(&Event::error(e.to_string())).await;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Checks the user is allowed to publish the given event.
+    fn check_publish(&self, event: &Event) -> Result<(), &'static str> {
+        if event.is_reserved() {
+            return Err("event type is reserved")
+        }
+
+        if !authz::can_publish(&self.user, &self.room) {
+            return Err("missing permission to publish events")
+        }
+
+        Ok(())
+    }
+}
+
+/// How a lagging connection catches up on the events it lost and the
+/// events which were still queued for it.
+#[derive(Debug)]
+struct CatchUp {
+    /// The events to deliver in order.
+    events: Vec<Arc<Event>>,
+
+    /// Whether a `RESYNC` event is sent after `events`.
+    resync: bool,
+
+    /// Whether any events without a coalesce key were lost, only these
+    /// count towards the `disconnect` policy's limit.
+    lost_unkeyed: bool,
+
+    /// The amount of events which are never delivered.
+    dropped: u64,
+}
+
+/// Works out which events a lagging connection is sent under the policy.
+///
+/// Built in events are always delivered and only the latest event of each
+/// coalesce key is, the other events the client wants depend on the policy.
+fn catch_up(
+    policy: LagPolicy,
+    lagged: Lagged,
+    skipped: u64,
+    wants: impl Fn(&Event) -> bool,
+) -> CatchUp {
+    let Lagged { lost, queued, .. } = lagged;
+
+    let lost_unkeyed = match lost.as_deref() {
+        Some(lost) => lost
+            .iter()
+            .any(|event| !event.is_reserved() && event.coalesce_key.is_none() && wants(event)),
+        None => true,
+    };
+
+    let resync = lost_unkeyed && match policy {
+        LagPolicy::Resync => true,
+        LagPolicy::Coalesce => lost.is_none(),
+        LagPolicy::Disconnect | LagPolicy::DropOldest => false,
+    };
+
+    let received = lost.as_ref().map(Vec::len).unwrap_or(skipped as usize) + queued.len();
+
+    // Resyncing clients re-fetch the state including every room event
+    // emitted up until now.
+    let lost = lost
+        .into_iter()
+        .flatten()
+        .filter(|event| {
+            event.is_reserved() || (!resync && (event.coalesce_key.is_some() || policy == LagPolicy::Coalesce))
+        });
+    let queued = queued
+        .into_iter()
+        .filter(|event| event.is_reserved() || event.seq.is_none() || !resync);
+    let candidates: Vec<Arc<Event>> = lost.chain(queued).collect();
+
+    let mut latest = HashMap::new();
+    for (i, event) in candidates.iter().enumerate() {
+        if let Some(key) = event.coalesce_key.as_deref() {
+            if wants(event) {
+                latest.insert(key, i);
+            }
+        }
+    }
+
+    let events: Vec<Arc<Event>> = candidates
+        .iter()
+        .enumerate()
+        .filter(|(i, event)| match event.coalesce_key.as_deref() {
+            Some(key) => latest.get(key) == Some(i),
+            None => true,
+        })
+        .map(|(_, event)| event.clone())
+        .collect();
+
+    CatchUp {
+        dropped: received.saturating_sub(events.len()) as u64,
+        events,
+        resync,
+        lost_unkeyed,
+    }
+}
+
+/// Picks the encoding from the `encoding` query param or the first
+/// `Sec-WebSocket-Protocol` requested by the client which is supported,
+/// returning the protocol to accept if one was requested.
+fn negotiate_encoding(
+    name: Option<&str>,
+    protocols: Option<&HeaderValue>,
+) -> Result<(Encoding, Option<&'static str>), ApiError> {
+    let requested = protocols
+        .and_then(|protocols| protocols.to_str().ok())
+        .and_then(|protocols| {
+            protocols
+                .split(',')
+                .find_map(|protocol| Encoding::from_protocol(protocol.trim()))
+        });
+
+    let named = match name {
+        Some(name) => Some(Encoding::from_name(name).ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "unknown encoding {}, expected json, json-text, msgpack or cbor",
+                name,
+            ))
+        })?),
+        None => None,
+    };
+
+    if let (Some(named), Some(requested)) = (named, requested) {
+        if named != requested {
+            return Err(ApiError::BadRequest(format!(
+                "the {} encoding doesn't match the requested protocol {}",
+                named.name(),
+                requested.protocol(),
+            )))
+        }
+    }
+
+    let encoding = named.or(requested).unwrap_or_default();
+    Ok((encoding, requested.map(|encoding| encoding.protocol())))
+}
+
+/// Waits for the next tick of the interval, never completing if there is none.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        },
+        None => futures_util::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(seq: u64, type_: &str, key: Option<&str>) -> Arc<Event> {
+        let mut event = Event::new(type_, json!({}));
+        event.set_seq(seq);
+        event.coalesce_key = key.map(str::to_string);
+        Arc::new(event)
+    }
+
+    fn lagged(lost: Option<Vec<Arc<Event>>>, queued: Vec<Arc<Event>>) -> Lagged {
+        Lagged {
+            seq: queued.iter().filter_map(|event| event.seq).max().unwrap_or(0),
+            lost,
+            queued,
+        }
+    }
+
+    fn summary(events: &[Arc<Event>]) -> Vec<(Option<u64>, &str)> {
+        events.iter().map(|event| (event.seq, event.type_.as_str())).collect()
+    }
+
+    /// Keyless `A`s and keyed `K`s, some of which were lost and some still queued.
+    fn mixed() -> Lagged {
+        lagged(
+            Some(vec![
+                event(1, "A", None),
+                event(2, "K", Some("key")),
+                event(3, "A", None),
+            ]),
+            vec![event(4, "K", Some("key")), event(5, "A", None)],
+        )
+    }
+
+    #[test]
+    fn disconnect_drops_lost_events() {
+        let catch_up = catch_up(LagPolicy::Disconnect, mixed(), 3, |_| true);
+
+        assert_eq!(summary(&catch_up.events), vec![(Some(4), "K"), (Some(5), "A")]);
+        assert!(catch_up.lost_unkeyed);
+        assert!(!catch_up.resync);
+        assert_eq!(catch_up.dropped, 3);
+    }
+
+    #[test]
+    fn drop_oldest_drops_lost_events() {
+        let catch_up = catch_up(LagPolicy::DropOldest, mixed(), 3, |_| true);
+
+        assert_eq!(summary(&catch_up.events), vec![(Some(4), "K"), (Some(5), "A")]);
+        assert!(!catch_up.resync);
+    }
+
+    #[test]
+    fn coalesce_delivers_lost_events_once() {
+        let catch_up = catch_up(LagPolicy::Coalesce, mixed(), 3, |_| true);
+
+        assert_eq!(
+            summary(&catch_up.events),
+            vec![(Some(1), "A"), (Some(3), "A"), (Some(4), "K"), (Some(5), "A")],
+        );
+        assert!(!catch_up.resync);
+        assert_eq!(catch_up.dropped, 1);
+    }
+
+    #[test]
+    fn coalesce_falls_back_to_resync() {
+        let lagged = lagged(None, vec![Arc::new(Event::ping(7)), event(8, "A", None)]);
+        let catch_up = catch_up(LagPolicy::Coalesce, lagged, 5, |_| true);
+
+        assert_eq!(summary(&catch_up.events), vec![(None, "PING")]);
+        assert!(catch_up.resync);
+        assert_eq!(catch_up.dropped, 6);
+    }
+
+    #[test]
+    fn resync_only_keeps_built_in_events() {
+        let lagged = lagged(
+            Some(vec![event(1, "A", None), event(2, "MEMBER_JOIN", None)]),
+            vec![event(3, "A", None), event(4, "K", Some("key")), Arc::new(Event::ping(1))],
+        );
+        let catch_up = catch_up(LagPolicy::Resync, lagged, 2, |_| true);
+
+        assert_eq!(summary(&catch_up.events), vec![(Some(2), "MEMBER_JOIN"), (None, "PING")]);
+        assert!(catch_up.resync);
+    }
+
+    #[test]
+    fn lost_keyed_events_are_not_counted() {
+        let lagged = lagged(
+            Some(vec![event(1, "K", Some("key")), event(2, "MEMBER_LEAVE", None)]),
+            vec![event(3, "K", Some("key"))],
+        );
+        let catch_up = catch_up(LagPolicy::Resync, lagged, 2, |_| true);
+
+        assert_eq!(summary(&catch_up.events), vec![(Some(2), "MEMBER_LEAVE"), (Some(3), "K")]);
+        assert!(!catch_up.lost_unkeyed);
+        assert!(!catch_up.resync);
+    }
+
+    #[test]
+    fn lost_unwanted_events_are_not_counted() {
+        let lagged = lagged(Some(vec![event(1, "A", None)]), vec![event(2, "B", None)]);
+        let catch_up = catch_up(LagPolicy::Disconnect, lagged, 1, |event| event.type_ != "A");
+
+        assert!(!catch_up.lost_unkeyed);
+    }
+
+    #[test]
+    fn only_the_latest_wanted_event_of_each_key_is_kept() {
+        let lagged = lagged(
+            Some(vec![event(1, "K", Some("key"))]),
+            vec![event(2, "K", Some("key")), event(3, "HIDDEN", Some("key"))],
+        );
+        let catch_up = catch_up(LagPolicy::Coalesce, lagged, 1, |event| event.type_ != "HIDDEN");
+
+        assert_eq!(summary(&catch_up.events), vec![(Some(2), "K")]);
+    }
 }